@@ -0,0 +1,101 @@
+// Service worker for the payloads of web_notif in the "angular" format:
+// {"notification": {title, ..., actions: [{action, title, icon}], data: {onActionClick, tracking}}}
+// WEBPUSH_CONFIG is prepended by the server: {version, serverUrl, trackEvents}
+'use strict';
+
+const WEBPUSH_SW_VERSION = WEBPUSH_CONFIG.version;
+
+// Notification options accepted by showNotification, named as in the payload
+const WEBPUSH_OPTIONS = [
+  'badge', 'body', 'dir', 'data', 'icon', 'image', 'lang', 'renotify', 'requireInteraction',
+  'silent', 'tag', 'timestamp', 'vibrate', 'actions',
+];
+
+function webpushOptions(notification) {
+  const options = {};
+  for (const option of WEBPUSH_OPTIONS) {
+    if (notification[option] !== undefined && notification[option] !== null) {
+      options[option] = notification[option];
+    }
+  }
+  return options;
+}
+
+// Reports the event to POST /events. Notifications without data.tracking aren't reported
+function webpushReport(data, event) {
+  const tracking = data && data.tracking;
+  if (!WEBPUSH_CONFIG.trackEvents || !tracking) {
+    return Promise.resolve();
+  }
+  return fetch(`${WEBPUSH_CONFIG.serverUrl}/events`, {
+    method: 'POST',
+    headers: { 'content-type': 'application/json' },
+    body: JSON.stringify({ ...tracking, event }),
+  }).catch(() => {});
+}
+
+self.addEventListener('push', (event) => {
+  let payload;
+  try {
+    payload = event.data ? event.data.json() : null;
+  } catch (e) {
+    payload = null;
+  }
+  // Empty and raw pushes are left to other listeners
+  if (!payload || !payload.notification || !payload.notification.title) {
+    return;
+  }
+
+  const { title } = payload.notification;
+  const options = webpushOptions(payload.notification);
+  event.waitUntil(
+    self.registration.showNotification(title, options)
+      .then(() => webpushReport(options.data, 'shown')),
+  );
+});
+
+async function webpushLastFocused() {
+  const windows = await self.clients.matchAll({ type: 'window', includeUncontrolled: true });
+  return windows.find((c) => c.focused) || windows[0];
+}
+
+// Same operations as Angular's service worker
+async function webpushClick(click) {
+  const url = new URL(click.url || '', self.location.origin).href;
+  switch (click.operation) {
+    case 'openWindow':
+      return self.clients.openWindow(url);
+    case 'focusLastFocusedOrOpen': {
+      const client = await webpushLastFocused();
+      return client ? client.focus() : self.clients.openWindow(url);
+    }
+    case 'navigateLastFocusedOrOpen': {
+      const client = await webpushLastFocused();
+      if (!client) {
+        return self.clients.openWindow(url);
+      }
+      const navigated = await client.navigate(url);
+      return (navigated || client).focus();
+    }
+    case 'sendRequest':
+      return fetch(url);
+    default:
+      return undefined;
+  }
+}
+
+self.addEventListener('notificationclick', (event) => {
+  const { data } = event.notification;
+  const action = event.action || 'default';
+  const click = data && data.onActionClick && data.onActionClick[action];
+  event.notification.close();
+
+  event.waitUntil(Promise.all([
+    click ? webpushClick(click) : Promise.resolve(),
+    webpushReport(data, event.action ? `action:${event.action}` : 'clicked'),
+  ]));
+});
+
+self.addEventListener('notificationclose', (event) => {
+  event.waitUntil(webpushReport(event.notification.data, 'closed'));
+});
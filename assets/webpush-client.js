@@ -0,0 +1,74 @@
+// Subscribes the browser and hands the subscription to the site backend, which sends it to web_notif
+// WEBPUSH_CONFIG is prepended by the server: {version, serverUrl, registerUrl}
+'use strict';
+
+const WebPushClient = (() => {
+  function keyBytes(key) {
+    const base64 = (key + '='.repeat((4 - (key.length % 4)) % 4)).replace(/-/g, '+').replace(/_/g, '/');
+    return Uint8Array.from(atob(base64), (c) => c.charCodeAt(0));
+  }
+
+  async function publicKey() {
+    const response = await fetch(`${WEBPUSH_CONFIG.serverUrl}/get_public_key`);
+    if (!response.ok) {
+      throw new Error(`web_notif public key couldn't be fetched: ${response.status}`);
+    }
+    return (await response.text()).trim();
+  }
+
+  // Same shape as the subscription of /notify and /topics/{name}/subscriptions
+  function toSubscription(subscription) {
+    const { endpoint, keys } = subscription.toJSON();
+    const encodings = PushManager.supportedContentEncodings || ['aes128gcm'];
+    return {
+      endpoint,
+      keys,
+      content_encoding: encodings.includes('aes128gcm') ? 'aes128gcm' : 'aesgcm',
+      format: 'angular',
+      capabilities: {
+        max_actions: Notification.maxActions,
+        supports_images: 'image' in Notification.prototype,
+      },
+    };
+  }
+
+  // options: serviceWorker (url of the site's service worker, default /sw.js), scope, registerUrl
+  async function subscribe(options = {}) {
+    if (!('serviceWorker' in navigator) || !('PushManager' in window)) {
+      throw new Error('Push notifications are not supported by this browser');
+    }
+    const registration = await navigator.serviceWorker.register(options.serviceWorker || '/sw.js', { scope: options.scope || '/' });
+    await navigator.serviceWorker.ready;
+
+    let subscription = await registration.pushManager.getSubscription();
+    if (!subscription) {
+      subscription = await registration.pushManager.subscribe({
+        userVisibleOnly: true,
+        applicationServerKey: keyBytes(await publicKey()),
+      });
+    }
+
+    const body = toSubscription(subscription);
+    const registerUrl = options.registerUrl || WEBPUSH_CONFIG.registerUrl;
+    if (registerUrl) {
+      const response = await fetch(registerUrl, {
+        method: 'POST',
+        credentials: 'include',
+        headers: { 'content-type': 'application/json' },
+        body: JSON.stringify(body),
+      });
+      if (!response.ok) {
+        throw new Error(`Subscription couldn't be registered: ${response.status}`);
+      }
+    }
+    return body;
+  }
+
+  async function unsubscribe(options = {}) {
+    const registration = await navigator.serviceWorker.getRegistration(options.scope || '/');
+    const subscription = registration && await registration.pushManager.getSubscription();
+    return subscription ? subscription.unsubscribe() : false;
+  }
+
+  return { version: WEBPUSH_CONFIG.version, subscribe, unsubscribe };
+})();
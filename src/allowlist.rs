@@ -0,0 +1,179 @@
+use std::{net::{IpAddr, SocketAddr}, sync::Arc};
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use url::Url;
+
+use crate::conf::EndpointsConf;
+
+///Verifica que el endpoint sea https, de un servicio de push conocido y que no resuelva a una ip interna.
+///Devuelve el motivo del rechazo
+pub async fn check(conf: &EndpointsConf, endpoint: &str) -> Result<(), String> {
+    let Some(url) = check_url(conf, endpoint)? else {
+        return Ok(());
+    };
+
+    //Resolver y rechazar direcciones privadas, por si el dns de un dominio permitido apunta adentro
+    let host = url.host_str().ok_or("has no host")?;
+    resolve_public(host, url.port_or_known_default().unwrap_or(443)).await?;
+    Ok(())
+}
+
+///Solo el esquema y el dominio, sin resolver. Al enviar alcanza con esto, el cliente verifica las direcciones al conectarse.
+///Devuelve la url si enforce esta activo
+pub fn check_url(conf: &EndpointsConf, endpoint: &str) -> Result<Option<Url>, String> {
+    if !conf.enforce {
+        return Ok(None);
+    }
+
+    let url = Url::parse(endpoint).map_err(|e| format!("is not a valid URL: {e}"))?;
+    if url.scheme() != "https" {
+        return Err("must use https".into());
+    }
+
+    let host = url.host_str().ok_or("has no host")?;
+    let allowed = conf.allowed_domains.iter()
+        .any(|d| host == d || host.strip_suffix(d.as_str()).is_some_and(|sub| sub.ends_with('.')));
+    if !allowed {
+        return Err(format!("{host} is not an allowed push service"));
+    }
+    Ok(Some(url))
+}
+
+///Url de un webhook: https y con direccion publica. Sin enforce alcanza con que sea http(s), para pruebas locales
+pub async fn check_webhook(conf: &EndpointsConf, webhook: &str) -> Result<(), String> {
+    let url = Url::parse(webhook).map_err(|e| format!("is not a valid URL: {e}"))?;
+    if !conf.enforce {
+        return match url.scheme() {
+            "https" | "http" => Ok(()),
+            _ => Err("must be an http(s) URL".into()),
+        };
+    }
+    if url.scheme() != "https" {
+        return Err("must use https".into());
+    }
+
+    let host = url.host_str().ok_or("has no host")?;
+    resolve_public(host, url.port_or_known_default().unwrap_or(443)).await?;
+    Ok(())
+}
+
+///Resuelve el host y falla si alguna direccion no es publica
+async fn resolve_public(host: &str, port: u16) -> Result<Vec<SocketAddr>, String> {
+    //Las ip de Url::host_str de IPv6 vienen entre corchetes
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await
+        .map_err(|e| format!("{host} couldn't be resolved: {e}"))?
+        .collect();
+    if let Some(addr) = addrs.iter().find(|a| !is_public(a.ip())) {
+        return Err(format!("{host} resolves to the non public address {}", addr.ip()));
+    }
+    Ok(addrs)
+}
+
+///Cliente http para push services y webhooks. Con enforce las direcciones se verifican al conectarse, asi un
+///segundo lookup que devuelva otra ip (dns rebinding) no llega a una direccion interna. No sigue redirecciones
+pub fn client(conf: &EndpointsConf, builder: reqwest::ClientBuilder) -> reqwest::Client {
+    let builder = builder.redirect(reqwest::redirect::Policy::none());
+    let builder = if conf.enforce { builder.dns_resolver(Arc::new(PublicResolver)) } else { builder };
+    builder.build().unwrap()
+}
+
+///Resolver que rechaza las direcciones no publicas
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_owned();
+        Box::pin(async move {
+            let addrs = resolve_public(&host, 0).await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()
+                || ip.is_broadcast() || ip.is_documentation() || ip.is_multicast()
+                //0.0.0.0/8 "this network"
+                || a == 0
+                //100.64.0.0/10, carrier grade NAT
+                || (a == 100 && (64..128).contains(&b))
+                //192.0.0.0/24, asignaciones de protocolos del IETF
+                || (a == 192 && b == 0 && c == 0)
+                //198.18.0.0/15, benchmarking
+                || (a == 198 && (b & 0xfe) == 18)
+                //240.0.0.0/4, reserved
+                || a >= 240)
+        },
+        IpAddr::V6(ip) => {
+            if let Some(v4) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(v4));
+            }
+            let segments = ip.segments();
+            let [first, second, ..] = segments;
+            !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()
+                //fc00::/7 unique local, fe80::/10 link local
+                || (first & 0xfe00) == 0xfc00 || (first & 0xffc0) == 0xfe80
+                //2002::/16 6to4 y 2001::/32 Teredo, llevan una IPv4 adentro que puede ser privada
+                || first == 0x2002 || (first == 0x2001 && second == 0)
+                //2001:db8::/32, documentacion
+                || (first == 0x2001 && second == 0xdb8)
+                //64:ff9b::/96 NAT64, el gateway traduce a cualquier IPv4
+                || segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0])
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public(ip: &str) -> bool {
+        is_public(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn rejects_non_public_addresses() {
+        for ip in [
+            "0.0.0.0", "0.1.2.3", "10.0.0.1", "100.64.0.1", "127.0.0.1", "169.254.169.254", "172.16.0.1",
+            "192.168.1.1", "192.0.0.1", "192.0.0.170", "198.18.0.1", "198.19.255.255", "224.0.0.1", "240.0.0.1", "255.255.255.255",
+            "::", "::1", "fc00::1", "fe80::1", "ff02::1", "::ffff:10.0.0.1", "64:ff9b::a00:1", "64:ff9b::808:808",
+            "2002:a00:1::1", "2002:808:808::1", "2001::1", "2001:0:4136:e378:8000:63bf:3fff:fdd2", "2001:db8::1", "2001:db8:ffff::1",
+        ] {
+            assert!(!public(ip), "{ip} should not be public");
+        }
+    }
+
+    #[test]
+    fn accepts_public_addresses() {
+        for ip in [
+            "8.8.8.8", "100.128.0.1", "142.250.0.1", "192.0.1.1", "198.20.0.1", "223.255.255.255",
+            "2a00:1450:4001::1", "2001:4860:4860::8888", "2003::1", "::ffff:8.8.8.8",
+        ] {
+            assert!(public(ip), "{ip} should be public");
+        }
+    }
+
+    #[test]
+    fn matches_allowed_domains_and_their_subdomains() {
+        let conf = EndpointsConf::default();
+        assert!(check_url(&conf, "https://fcm.googleapis.com/fcm/send/abc").is_ok());
+        assert!(check_url(&conf, "https://web.push.apple.com/abc").is_ok());
+        assert!(check_url(&conf, "https://wns2-par02p.notify.windows.com/w/?token=abc").is_ok());
+
+        assert!(check_url(&conf, "https://evilfcm.googleapis.com/abc").is_err());
+        assert!(check_url(&conf, "https://fcm.googleapis.com.evil.com/abc").is_err());
+        assert!(check_url(&conf, "https://googleapis.com/abc").is_err());
+        assert!(check_url(&conf, "http://fcm.googleapis.com/fcm/send/abc").is_err());
+        assert!(check_url(&conf, "not a url").is_err());
+    }
+
+    #[test]
+    fn accepts_any_endpoint_without_enforce() {
+        let conf = EndpointsConf { enforce: false, ..Default::default() };
+        assert!(matches!(check_url(&conf, "http://127.0.0.1:8080/push"), Ok(None)));
+    }
+}
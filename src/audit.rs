@@ -0,0 +1,192 @@
+use std::{fs::{self, OpenOptions}, io::Write, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::Duration};
+
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, trace};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{conf::{AuditConf, data_path}, request_id, scheduler::now_millis, state::AppState};
+
+///Cada cuanto se borran las entradas vencidas
+const PRUNE_EVERY: Duration = Duration::from_secs(60 * 60);
+const DEFAULT_LIMIT: usize = 1000;
+
+///Who asked for a push and what it carries
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone, Default)]
+pub struct SendContext {
+    ///Name of the api key
+    #[serde(default)]
+    pub caller      : String,
+    ///X-Request-Id of the request that asked for the push
+    #[serde(default)]
+    pub request_id  : String,
+    ///Id of the template used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template    : Option<String>,
+    ///Hex SHA-256 of the notification title
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title_hash  : Option<String>,
+    ///Intentos anteriores de un push programado. No se guarda, el scheduler lo completa en cada intento
+    #[serde(skip)]
+    pub attempts    : u32,
+    ///Reintentos que quedan si este intento falla con un error transitorio
+    #[serde(skip)]
+    pub retries_left: u32,
+}
+
+impl SendContext {
+    ///Contexto de la request actual
+    pub fn new(caller: &str, template: Option<&str>, title: Option<&str>) -> Self {
+        Self {
+            caller      : caller.to_owned(),
+            request_id  : request_id::current(),
+            template    : template.map(str::to_owned),
+            title_hash  : title.map(|t| format!("{:x}", Sha256::digest(t.as_bytes()))),
+            attempts    : 0,
+            retries_left: 0,
+        }
+    }
+}
+
+///One push attempt
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone)]
+pub struct AuditEntry {
+    ///Unix time in milliseconds
+    pub time      : u64,
+    #[serde(flatten)]
+    pub context   : SendContext,
+    ///Endpoint of the subscription
+    pub target    : String,
+    ///`sent` or the error of the attempt, as in the metrics
+    pub status    : String,
+    pub latency_ms: u64,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct AuditQuery {
+    ///Unix time in milliseconds, inclusive
+    pub from  : Option<u64>,
+    ///Unix time in milliseconds, exclusive
+    pub to    : Option<u64>,
+    ///Subscription endpoint
+    pub target: Option<String>,
+    ///Maximum amount of entries, newest first. Defaults to 1000
+    pub limit : Option<usize>,
+}
+
+///Registro de envios en audit.jsonl junto al ejecutable. Solo se agregan lineas, salvo al borrar las vencidas.
+///Las escrituras y la limpieza las hace el worker, asi los envios no esperan al disco
+pub struct Audit {
+    path     : PathBuf,
+    sender   : UnboundedSender<AuditEntry>,
+    ///Lo toma run al arrancar
+    receiver : Mutex<Option<UnboundedReceiver<AuditEntry>>>,
+    retention: Duration,
+}
+
+impl Audit {
+    pub fn load(conf: AuditConf) -> Self {
+        let (sender, receiver) = unbounded_channel();
+        Self {
+            path     : data_path("audit.jsonl"),
+            sender,
+            receiver : Mutex::new(Some(receiver)),
+            retention: Duration::from_secs(conf.retention_days * 24 * 60 * 60),
+        }
+    }
+
+    ///Encola la entrada, la escribe el worker
+    pub fn record(&self, entry: &AuditEntry) {
+        if self.sender.send(entry.clone()).is_err() {
+            tracing::error!("Audit worker stopped, entry for request {} lost", entry.context.request_id);
+        }
+    }
+
+    ///Entradas de la api key, las mas nuevas primero. Lee el archivo en otro thread y sin lock: las lineas se
+    ///agregan enteras y la limpieza reemplaza el archivo con un rename, una linea a medio escribir se ignora
+    pub async fn query(&self, caller: &str, query: &AuditQuery) -> Vec<AuditEntry> {
+        let path = self.path.clone();
+        let entries = tokio::task::spawn_blocking(move || read(&path)).await.unwrap_or_default();
+        let mut entries: Vec<AuditEntry> = entries
+            .into_iter()
+            .filter(|e| e.context.caller == caller)
+            .filter(|e| query.from.is_none_or(|from| e.time >= from))
+            .filter(|e| query.to.is_none_or(|to| e.time < to))
+            .filter(|e| query.target.as_ref().is_none_or(|t| &e.target == t))
+            .collect();
+        entries.reverse();
+        entries.truncate(query.limit.unwrap_or(DEFAULT_LIMIT));
+        entries
+    }
+
+    fn append(&self, entries: &[AuditEntry]) {
+        let text: String = entries.iter().map(|e| serde_json::to_string(e).unwrap() + "\n").collect();
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(text.as_bytes()));
+        if let Err(e) = result {
+            tracing::error!("{} couldn't be written: {}", self.path.display(), e);
+        }
+    }
+
+    ///Reescribe el archivo sin las entradas anteriores a la retencion
+    fn prune(&self) {
+        let oldest = now_millis().saturating_sub(self.retention.as_millis() as u64);
+        let entries = read(&self.path);
+        let keep: Vec<&AuditEntry> = entries.iter().filter(|e| e.time >= oldest).collect();
+        if keep.len() == entries.len() {
+            return;
+        }
+
+        let text: String = keep.iter().map(|e| serde_json::to_string(e).unwrap() + "\n").collect();
+        let tmp = self.path.with_extension("jsonl.tmp");
+        match fs::write(&tmp, text).and_then(|_| fs::rename(&tmp, &self.path)) {
+            Ok(_) => debug!("{} audit entries removed by retention", entries.len() - keep.len()),
+            Err(e) => tracing::error!("{} couldn't be pruned: {}", self.path.display(), e),
+        }
+    }
+}
+
+fn read(path: &Path) -> Vec<AuditEntry> {
+    match fs::read_to_string(path) {
+        Ok(text) => text.lines().filter_map(|line| serde_json::from_str(line).ok()).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+///Escribe las entradas encoladas y borra las vencidas periodicamente. Es el unico que modifica el archivo
+pub async fn run(state: Arc<AppState>) {
+    trace!("Audit worker started");
+    let Some(mut receiver) = state.audit.receiver.lock().unwrap().take() else {
+        return;
+    };
+    let mut prune = tokio::time::interval(PRUNE_EVERY);
+    loop {
+        tokio::select! {
+            _ = prune.tick() => blocking(&state, Audit::prune).await,
+            received = receiver.recv() => {
+                let Some(entry) = received else {
+                    return;
+                };
+                //Se escriben juntas las que se acumularon mientras tanto
+                let mut entries = vec![entry];
+                while let Ok(entry) = receiver.try_recv() {
+                    entries.push(entry);
+                }
+                blocking(&state, move |audit| audit.append(&entries)).await;
+            },
+        }
+    }
+}
+
+///Corre la escritura fuera de los threads de tokio
+async fn blocking(state: &Arc<AppState>, task: impl FnOnce(&Audit) + Send + 'static) {
+    let state = state.clone();
+    if let Err(e) = tokio::task::spawn_blocking(move || task(&state.audit)).await {
+        tracing::error!("Audit log task failed: {}", e);
+    }
+}
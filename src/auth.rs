@@ -1,13 +1,20 @@
 use std::sync::Arc;
 
-use axum::{extract::{Request, State}, http::StatusCode, middleware::Next, response::Response};
+use axum::{extract::{Request, State}, http::StatusCode, middleware::Next, response::{IntoResponse, Response}};
 use tracing::info;
 
+use crate::{rate_limit::too_many_requests, state::AppState};
+
+///Name of the api key that authenticated the request, available as an extension
+#[derive(Clone, Debug)]
+pub struct Caller(pub String);
+
+#[tracing::instrument(skip_all)]
 pub async fn auth(
-    State(api_key): State<Arc<String>>,
-    req: Request, 
+    State(state): State<Arc<AppState>>,
+    mut req: Request, 
     next: Next
-) -> Result<Response, StatusCode> {
+) -> Result<Response, Response> {
     let auth_header = req.headers()
         .get("api_key")
         .and_then(|header| header.to_str().ok());
@@ -16,15 +23,23 @@ pub async fn auth(
         auth_header
     } else {
         info!("StatusCode::UNAUTHORIZED Missing api_key header");
-        return Err(StatusCode::UNAUTHORIZED);
+        return Err(StatusCode::UNAUTHORIZED.into_response());
     };
 
-    if auth_header == *api_key {
-        // If the API key matches, proceed to the next handler
-        Ok(next.run(req).await)
+    let api_key = if let Some(api_key) = state.api_keys.iter().find(|k| k.key == auth_header) {
+        api_key
     } else {
         // Otherwise, return Unauthorized
         info!("StatusCode::UNAUTHORIZED api_key header doesn't match");
-        Err(StatusCode::UNAUTHORIZED)
+        return Err(StatusCode::UNAUTHORIZED.into_response());
+    };
+
+    if let Err(retry_after) = state.rate_limits.check_api_key(api_key) {
+        info!("StatusCode::TOO_MANY_REQUESTS api_key {} exceeded its rate limit", api_key.name);
+        return Err(too_many_requests(retry_after));
     }
-}
\ No newline at end of file
+
+    // If the API key matches, proceed to the next handler
+    req.extensions_mut().insert(Caller(api_key.name.clone()));
+    Ok(next.run(req).await)
+}
@@ -1,19 +1,28 @@
-use std::fs;
+use std::{fs, path::PathBuf};
 
 use base64::Engine;
 use ::base64::prelude;
 use tracing::{debug, trace};
 use openssl::{bn::BigNumContext, ec::{EcGroup, EcKey, PointConversionForm}, nid::Nid};
 use serde::{Deserialize, Serialize};
+use rolling_file::{BasicRollingFileAppender, RollingConditionBasic};
 use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{EnvFilter, Layer, fmt::writer::BoxMakeWriter, layer::SubscriberExt, util::SubscriberInitExt};
 use utoipa::openapi::Contact;
 
-pub fn load_conf_file() -> ConfFile {
-    let conf_path = std::env::current_exe().unwrap()
+use crate::{rate_limit::Limit, telemetry};
+
+///Path of a file stored next to the executable
+pub fn data_path(file_name: &str) -> PathBuf {
+    std::env::current_exe().unwrap()
     .parent()
     .unwrap()
     .to_path_buf()
-    .join("conf.json");
+    .join(file_name)
+}
+
+pub fn load_conf_file() -> ConfFile {
+    let conf_path = data_path("conf.json");
     
     trace!("Searching for conf.json at {:?}", &conf_path);
     match fs::read(&conf_path) {
@@ -21,7 +30,7 @@ pub fn load_conf_file() -> ConfFile {
             trace!("conf.json found");
             match serde_json::from_slice::<ConfFile>(&b) {
                 Ok(k) => {
-                    init_logging(k.server.trace_level);
+                    init_logging(k.server.trace_level, &k.logging, &k.telemetry);
                     
                     k
                 },
@@ -31,7 +40,7 @@ pub fn load_conf_file() -> ConfFile {
             }
         },
         Err(_) => {
-            init_logging(TraceLevel::TRACE);
+            init_logging(TraceLevel::TRACE, &LoggingConf::default(), &TelemetryConf::default());
             debug!("conf.json couldn't be found. Creating {}, with newly made VAPID keys", conf_path.display());
 
             let keys = generate_vapid_keys().unwrap();
@@ -48,8 +57,22 @@ pub fn load_conf_file() -> ConfFile {
                     trace_level: TraceLevel::TRACE,
                     accept_from: "0.0.0.0".to_owned(),
                     port: 1000,
-                    api_key: "ApiKey_ArchiSecreta".to_owned()
-                } 
+                    api_key: "ApiKey_ArchiSecreta".to_owned(),
+                    api_keys: Vec::new(),
+                },
+                templates: TemplatesConf::default(),
+                rate_limits: RateLimitsConf::default(),
+                payload: PayloadConf::default(),
+                endpoints: EndpointsConf::default(),
+                idempotency: IdempotencyConf::default(),
+                metrics: MetricsConf::default(),
+                health: HealthConf::default(),
+                logging: LoggingConf::default(),
+                telemetry: TelemetryConf::default(),
+                audit: AuditConf::default(),
+                webhooks: WebhooksConf::default(),
+                events: EventsConf::default(),
+                service_worker: ServiceWorkerConf::default(),
             };
             let parsed = serde_json::to_string(&conf).unwrap();
             match fs::write(&conf_path, parsed) {
@@ -69,9 +92,35 @@ pub fn load_conf_file() -> ConfFile {
 
 #[derive(Deserialize, Serialize)]
 pub struct ConfFile {
-    pub openapi: OpenApi,
-    pub keys   : KeysJson,
-    pub server : Server,
+    pub openapi       : OpenApi,
+    pub keys          : KeysJson,
+    pub server        : Server,
+    #[serde(default)]
+    pub templates     : TemplatesConf,
+    #[serde(default)]
+    pub rate_limits   : RateLimitsConf,
+    #[serde(default)]
+    pub payload       : PayloadConf,
+    #[serde(default)]
+    pub endpoints     : EndpointsConf,
+    #[serde(default)]
+    pub idempotency   : IdempotencyConf,
+    #[serde(default)]
+    pub metrics       : MetricsConf,
+    #[serde(default)]
+    pub health        : HealthConf,
+    #[serde(default)]
+    pub logging       : LoggingConf,
+    #[serde(default)]
+    pub telemetry     : TelemetryConf,
+    #[serde(default)]
+    pub audit         : AuditConf,
+    #[serde(default)]
+    pub webhooks      : WebhooksConf,
+    #[serde(default)]
+    pub events        : EventsConf,
+    #[serde(default)]
+    pub service_worker: ServiceWorkerConf,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -88,6 +137,255 @@ pub struct Server {
     pub accept_from: String,
     pub port       : u16,
     pub api_key    : String,
+    ///Additional named keys. api_key is accepted with the name "default"
+    #[serde(default)]
+    pub api_keys   : Vec<ApiKey>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ApiKey {
+    pub name      : String,
+    pub key       : String,
+    ///Overrides rate_limits.api_key for this key
+    #[serde(default)]
+    pub rate_limit: Option<Limit>,
+}
+
+///Limits are disabled when not set
+#[derive(Deserialize, Serialize, Default)]
+pub struct RateLimitsConf {
+    ///Requests per api key
+    pub api_key     : Option<Limit>,
+    ///Pushes per target subscription, protects end users from spam
+    pub subscription: Option<Limit>,
+    ///Pushes per push service origin, ej: https://fcm.googleapis.com
+    pub push_service: Option<Limit>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct TemplatesConf {
+    ///Locales tried in order when a template has no variant for the requested one
+    pub fallback_locales: Vec<String>,
+}
+
+impl Default for TemplatesConf {
+    fn default() -> Self {
+        Self { fallback_locales: vec!["en".to_owned()] }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct PayloadConf {
+    ///Maximum size of the encrypted payload accepted by push services
+    pub max_size           : usize,
+    ///Fields removed, in order, until the payload fits. Empty to reject oversized payloads
+    pub trim               : Vec<TrimField>,
+    ///requireInteraction used when the notification doesn't set it
+    pub require_interaction: Option<bool>,
+}
+
+impl Default for PayloadConf {
+    fn default() -> Self {
+        Self { max_size: 4096, trim: Vec::new(), require_interaction: None }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+#[serde(rename_all="camelCase")]
+pub enum TrimField {
+    ///Drops the application data. Actions are kept
+    Data,
+    ///Shortens the body, ending it with "…"
+    Body,
+}
+
+///Push services that subscriptions may point to
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct EndpointsConf {
+    ///Set to false only in test setups to allow any endpoint, including http and private addresses
+    pub enforce             : bool,
+    ///Domains and their subdomains accepted as endpoint hosts
+    pub allowed_domains     : Vec<String>,
+    ///Seconds to wait for the connection to a push service
+    pub connect_timeout_secs: u64,
+    ///Seconds to wait for a push service to answer, including the connection
+    pub timeout_secs        : u64,
+}
+
+impl Default for EndpointsConf {
+    fn default() -> Self {
+        Self {
+            enforce: true,
+            allowed_domains: [
+                "fcm.googleapis.com",
+                "android.googleapis.com",
+                "push.services.mozilla.com",
+                "push.apple.com",
+                "notify.windows.com",
+            ].into_iter().map(String::from).collect(),
+            connect_timeout_secs: 10,
+            timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct IdempotencyConf {
+    ///Seconds a response is replayed for requests with the same Idempotency-Key
+    pub window_secs: u64,
+}
+
+impl Default for IdempotencyConf {
+    fn default() -> Self {
+        Self { window_secs: 24 * 60 * 60 }
+    }
+}
+
+#[derive(Deserialize, Serialize, Default)]
+pub struct MetricsConf {
+    ///When set /metrics requires `Authorization: Bearer <token>`. The api keys aren't accepted there
+    pub token: Option<String>,
+}
+
+///Subscription keys are never logged and endpoints are cut to their origin
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct LoggingConf {
+    ///Logs the notification (title, body, data...) of each notify request
+    #[serde(default)]
+    pub notification_content: bool,
+    #[serde(default)]
+    pub format              : LogFormat,
+    ///EnvFilter directives, ej: `warn,web_notif::push=debug`. Modules not listed use server.trace_level
+    #[serde(default)]
+    pub directives          : Option<String>,
+    ///Writes to a rotating file instead of stdout. Always on in Windows, with the default settings if not set
+    #[serde(default)]
+    pub file                : Option<LogFileConf>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Default)]
+#[serde(rename_all="camelCase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    ///One json object per line, with the fields of the current span and its parents
+    Json,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct LogFileConf {
+    ///Stored next to the executable
+    pub name       : String,
+    ///The file is rotated when it reaches this size
+    pub max_size_mb: u64,
+    ///Rotated files kept, older ones are deleted
+    pub max_files  : usize,
+}
+
+impl Default for LogFileConf {
+    fn default() -> Self {
+        Self { name: "webpush.log".to_owned(), max_size_mb: 10, max_files: 5 }
+    }
+}
+
+///OpenTelemetry export of the request, auth, notify and push send spans
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct TelemetryConf {
+    ///OTLP/HTTP traces endpoint, ej: http://localhost:4318/v1/traces. Export is disabled when not set
+    pub endpoint    : Option<String>,
+    ///Fraction of new traces exported, between 0 and 1. Requests with traceparent follow the caller's decision
+    pub sample_ratio: f64,
+    pub service_name: String,
+}
+
+impl Default for TelemetryConf {
+    fn default() -> Self {
+        Self { endpoint: None, sample_ratio: 1.0, service_name: "web_notif".to_owned() }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct AuditConf {
+    ///Days each entry of audit.jsonl is kept
+    pub retention_days: u64,
+}
+
+impl Default for AuditConf {
+    fn default() -> Self {
+        Self { retention_days: 90 }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct WebhooksConf {
+    ///Attempts per event before giving up. The wait between them doubles, starting at 1 second
+    pub max_attempts: u32,
+    ///Seconds to wait for the webhook to answer
+    pub timeout_secs: u64,
+}
+
+impl Default for WebhooksConf {
+    fn default() -> Self {
+        Self { max_attempts: 5, timeout_secs: 10 }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct EventsConf {
+    ///Adds the tracking data POST /events needs to the data of every notification. Off by default, it changes the payload and the /notify response
+    pub enabled       : bool,
+    ///Days the stats of a message are kept after its last event
+    pub retention_days: u64,
+}
+
+impl Default for EventsConf {
+    fn default() -> Self {
+        Self { enabled: false, retention_days: 30 }
+    }
+}
+
+///Values written into /sw.js and /webpush-client.js
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ServiceWorkerConf {
+    ///Url of this server as seen by the browsers. Defaults to the Host of the request for the script
+    pub public_url  : Option<String>,
+    ///Url of the site backend that receives the subscription from webpush-client.js
+    pub register_url: Option<String>,
+    ///The service worker reports shown, clicked and closed notifications to POST /events
+    pub track_events: bool,
+}
+
+impl Default for ServiceWorkerConf {
+    fn default() -> Self {
+        Self { public_url: None, register_url: None, track_events: true }
+    }
+}
+
+///Push success rate required by /readyz
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct HealthConf {
+    ///Seconds of push results considered
+    pub window_secs     : u64,
+    ///Below this amount of pushes in the window the check always passes
+    pub min_samples     : usize,
+    ///Between 0 and 1. Expired subscriptions don't count as failures
+    pub min_success_rate: f64,
+}
+
+impl Default for HealthConf {
+    fn default() -> Self {
+        Self { window_secs: 300, min_samples: 20, min_success_rate: 0.5 }
+    }
 }
 
 #[derive(Deserialize, Serialize)]
@@ -98,14 +396,18 @@ pub struct KeysJson {
 
 #[derive(Deserialize, Clone, Copy, Serialize)]
 pub enum TraceLevel {
-    DEBUG,
+    ERROR,
+    WARN,
     INFO,
+    DEBUG,
     TRACE,
 }
 
 impl Into<LevelFilter> for TraceLevel {
     fn into(self) -> LevelFilter {
         match self {
+            TraceLevel::ERROR => LevelFilter::ERROR,
+            TraceLevel::WARN  => LevelFilter::WARN,
             TraceLevel::DEBUG => LevelFilter::DEBUG,
             TraceLevel::INFO  => LevelFilter::INFO,
             TraceLevel::TRACE => LevelFilter::TRACE,
@@ -148,32 +450,42 @@ fn generate_vapid_keys() -> Result<KeysJson, Box<dyn std::error::Error>> {
 
 
 
-fn init_logging(level:TraceLevel) {
-    #[cfg(windows)]
-    {
-        let location = std::env::current_exe().unwrap();
-        let base_location = location.parent().unwrap();
-        let file_appender = tracing_appender::rolling::daily(
-            base_location,
-            "webpush.log",
-        );
+fn init_logging(level:TraceLevel, conf: &LoggingConf, telemetry_conf: &TelemetryConf) {
+    //trace_level es el nivel por defecto, las directivas lo cambian por modulo
+    let filter = EnvFilter::builder()
+        .with_default_directive(Into::<LevelFilter>::into(level).into())
+        .parse(conf.directives.as_deref().unwrap_or_default())
+        .unwrap_or_else(|e| panic!("logging.directives couldn't be parsed: {}", e));
 
-        let (non_blocking, _guard) =
-            tracing_appender::non_blocking(file_appender);
+    //Como servicio de Windows no hay consola, siempre se escribe a archivo
+    let file = conf.file.clone().or(if cfg!(windows) { Some(LogFileConf::default()) } else { None });
 
-        tracing_subscriber::fmt()
-            .with_max_level(level)
-            .with_writer(non_blocking)
-            .init();
+    let writer = match file {
+        Some(file) => {
+            let path = data_path(&file.name);
+            let appender = BasicRollingFileAppender::new(&path, RollingConditionBasic::new().max_size(file.max_size_mb * 1024 * 1024), file.max_files)
+                .unwrap_or_else(|e| panic!("{} couldn't be opened: {}", path.display(), e));
 
-        // IMPORTANT: keep guard alive
-        std::mem::forget(_guard);
-    }
+            let (non_blocking, _guard) =
+                tracing_appender::non_blocking(appender);
 
-    #[cfg(not(windows))]
-    {
-        tracing_subscriber::fmt()
-            .with_max_level(level)
-            .init();
-    }
-}
\ No newline at end of file
+            // IMPORTANT: keep guard alive
+            std::mem::forget(_guard);
+            BoxMakeWriter::new(non_blocking)
+        },
+        None => BoxMakeWriter::new(std::io::stdout),
+    };
+
+    let fmt = tracing_subscriber::fmt::layer().with_writer(writer);
+    let fmt = match conf.format {
+        LogFormat::Text => fmt.boxed(),
+        //La lista incluye el span request, con el id, aunque se loguee dentro de auth, notify o push_send
+        LogFormat::Json => fmt.json().with_current_span(true).with_span_list(true).boxed(),
+    };
+
+    //El filtro es solo de los logs, asi trace_level no apaga los spans que se exportan
+    tracing_subscriber::registry()
+        .with(fmt.with_filter(filter))
+        .with(telemetry::layer(telemetry_conf))
+        .init();
+}
@@ -0,0 +1,20 @@
+use axum::{extract::Request, http::{HeaderValue, Method, StatusCode, header}, middleware::Next, response::{IntoResponse, Response}};
+
+///Rutas que llaman los navegadores desde el origin del sitio, que no es el del servidor
+pub async fn cors(
+    req: Request,
+    next: Next
+) -> Response {
+    let mut response = if req.method() == Method::OPTIONS {
+        let mut response = StatusCode::NO_CONTENT.into_response();
+        let headers = response.headers_mut();
+        headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, HeaderValue::from_static("GET, POST"));
+        headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, HeaderValue::from_static("content-type"));
+        headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static("86400"));
+        response
+    } else {
+        next.run(req).await
+    };
+    response.headers_mut().insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+    response
+}
@@ -0,0 +1,308 @@
+use std::{collections::{BTreeMap, HashMap}, sync::{Arc, atomic::{AtomicBool, Ordering}}, time::Duration};
+
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sha2::Sha256;
+use tracing::trace;
+use utoipa::ToSchema;
+
+use crate::{conf::{EventsConf, KeysJson}, routes::notify::Notification, scheduler::now_millis, state::AppState, store::JsonStore};
+
+///Bytes del HMAC que se mandan en el token, alcanza para que no se pueda adivinar
+const TOKEN_BYTES: usize = 16;
+///Etiqueta de HKDF para la clave de los tokens, distinta de cualquier otro uso de la clave VAPID
+const KEY_INFO: &[u8] = b"webpush events tracking token";
+///Cada cuanto se guardan los eventos recibidos
+const FLUSH_EVERY: Duration = Duration::from_secs(10);
+
+///Identifies the message in the events sent by the service worker. Added to the notification data as `tracking`
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone)]
+pub struct Tracking {
+    ///Message id. The scheduled message id, the campaign id, or a new id returned when sending
+    pub id      : String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic   : Option<String>,
+    ///Ids of the action buttons of the notification. Only these are counted as `action:<id>`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub actions : Vec<String>,
+    ///Signature of the other fields, so only messages sent by this server are counted
+    pub token   : String,
+}
+
+///What the user did with the notification
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Shown,
+    Clicked,
+    Closed,
+    Action(String),
+}
+
+impl Event {
+    pub fn parse(event: &str) -> Result<Self, String> {
+        match event {
+            "shown" => Ok(Event::Shown),
+            "clicked" => Ok(Event::Clicked),
+            "closed" => Ok(Event::Closed),
+            _ => match event.strip_prefix("action:") {
+                Some(id) if !id.is_empty() => Ok(Event::Action(id.to_owned())),
+                Some(_) => Err("action id can't be empty".to_owned()),
+                None => Err("must be shown, clicked, closed or action:<id>".to_owned()),
+            },
+        }
+    }
+}
+
+///Pushes sent with tracking data and amount of each event
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone, Default)]
+pub struct Counters {
+    ///Pushes accepted by the push services. Each event is counted at most this many times
+    #[serde(default)]
+    pub sent   : u64,
+    pub shown  : u64,
+    pub clicked: u64,
+    pub closed : u64,
+    ///Clicks on each action button, by action id
+    #[serde(default)]
+    pub actions: BTreeMap<String, u64>,
+}
+
+impl Counters {
+    fn count(&mut self, event: &Event) -> &mut u64 {
+        match event {
+            Event::Shown => &mut self.shown,
+            Event::Clicked => &mut self.clicked,
+            Event::Closed => &mut self.closed,
+            Event::Action(id) => self.actions.entry(id.clone()).or_default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Default, Clone)]
+struct Stats {
+    ///Ultimo envio o evento, para borrar los que ya no se usan
+    last_event: u64,
+    ///Api key que envio el mensaje. Solo en messages, las de templates y topics ya estan agrupadas por caller
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    caller    : String,
+    #[serde(flatten)]
+    counters  : Counters,
+}
+
+///Los templates y topicos son de cada api key, asi que sus estadisticas van por caller y despues por nombre
+#[derive(Deserialize, Serialize, Default, Clone)]
+struct EventStats {
+    messages : HashMap<String, Stats>,
+    templates: BTreeMap<String, BTreeMap<String, Stats>>,
+    topics   : BTreeMap<String, BTreeMap<String, Stats>>,
+}
+
+impl EventStats {
+    ///Estadisticas del mensaje, su template y su topico, de caller
+    fn entries(&mut self, tracking: &Tracking, caller: &str, now: u64) -> Vec<&mut Stats> {
+        let message = self.messages.entry(tracking.id.clone()).or_insert_with(|| Stats { caller: caller.to_owned(), ..Default::default() });
+        let mut entries = vec![message];
+        if let Some(template) = &tracking.template {
+            entries.push(self.templates.entry(caller.to_owned()).or_default().entry(template.clone()).or_default());
+        }
+        if let Some(topic) = &tracking.topic {
+            entries.push(self.topics.entry(caller.to_owned()).or_default().entry(topic.clone()).or_default());
+        }
+        for entry in entries.iter_mut() {
+            entry.last_event = now;
+        }
+        entries
+    }
+
+    fn len(&self) -> usize {
+        self.messages.len() + self.templates.values().chain(self.topics.values()).map(BTreeMap::len).sum::<usize>()
+    }
+
+    ///Borra las que no tuvieron envios ni eventos desde oldest
+    fn retain(&mut self, oldest: u64) {
+        self.messages.retain(|_, s| s.last_event >= oldest);
+        for grouped in [&mut self.templates, &mut self.topics] {
+            grouped.retain(|_, owned| {
+                owned.retain(|_, s| s.last_event >= oldest);
+                !owned.is_empty()
+            });
+        }
+    }
+}
+
+///Why an event wasn't counted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejected {
+    ///No push was sent with this message id, or its stats were removed by retention
+    UnknownMessage,
+    ///The notification didn't have that action button
+    UnknownAction,
+    ///Every push of the message already reported this event
+    Exhausted,
+}
+
+///Grouping of the stats
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone, Copy)]
+#[serde(rename_all="snake_case")]
+pub enum StatsKind {
+    Messages,
+    Templates,
+    Topics,
+}
+
+///Eventos reportados por los service workers, persistidos en events.json junto al ejecutable
+pub struct Events {
+    ///Clave de los tokens, derivada de la clave privada VAPID con HKDF
+    key      : [u8; 32],
+    enabled  : bool,
+    retention: u64,
+    stats    : JsonStore<EventStats>,
+    ///Hay cambios sin guardar. Se guardan cada FLUSH_EVERY en vez de en cada evento
+    dirty    : AtomicBool,
+}
+
+impl Events {
+    pub fn load(conf: EventsConf, keys: &KeysJson) -> Self {
+        Self {
+            key      : derive_key(&keys.private_key),
+            enabled  : conf.enabled,
+            retention: conf.retention_days * 24 * 60 * 60 * 1000,
+            stats    : JsonStore::load("events.json"),
+            dirty    : AtomicBool::new(false),
+        }
+    }
+
+    ///Datos a agregar a la notificacion. None si el seguimiento esta deshabilitado o data no es un objeto
+    pub fn track(&self, id: &str, template: Option<&str>, topic: Option<&str>, notification: &Notification) -> Option<Tracking> {
+        if !self.enabled || notification.data.as_ref().is_some_and(|d| !d.is_object()) {
+            return None;
+        }
+        let mut tracking = Tracking {
+            id      : id.to_owned(),
+            template: template.map(str::to_owned),
+            topic   : topic.map(str::to_owned),
+            actions : notification.action_ids(),
+            token   : String::new(),
+        };
+        let tag = self.mac(&tracking).finalize().into_bytes();
+        tracking.token = BASE64_URL_SAFE_NO_PAD.encode(&tag[..TOKEN_BYTES]);
+        Some(tracking)
+    }
+
+    pub fn verify(&self, tracking: &Tracking) -> bool {
+        let Ok(token) = BASE64_URL_SAFE_NO_PAD.decode(&tracking.token) else {
+            return false;
+        };
+        token.len() == TOKEN_BYTES && self.mac(tracking).verify_truncated_left(&token).is_ok()
+    }
+
+    fn mac(&self, tracking: &Tracking) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        let actions = tracking.actions.join("\0");
+        //Separados por \0 para que no se puedan correr los limites entre campos
+        for field in ["events", &tracking.id, tracking.template.as_deref().unwrap_or_default(), tracking.topic.as_deref().unwrap_or_default(), &actions] {
+            mac.update(field.as_bytes());
+            mac.update(b"\0");
+        }
+        mac
+    }
+
+    ///Suma los pushes aceptados por el push service, que limitan cuantas veces se cuenta cada evento.
+    ///caller es la api key que envio el mensaje, la unica que despues puede ver sus estadisticas
+    pub fn sent(&self, tracking: &Tracking, caller: &str, count: u64) {
+        if count == 0 {
+            return;
+        }
+        let mut stats = self.stats.lock();
+        for entry in stats.entries(tracking, caller, now_millis()) {
+            entry.counters.sent += count;
+        }
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    ///Suma el evento al mensaje, su template y su topico. El token ya fue verificado
+    pub fn record(&self, tracking: &Tracking, event: &Event) -> Result<(), Rejected> {
+        if let Event::Action(id) = event && !tracking.actions.contains(id) {
+            return Err(Rejected::UnknownAction);
+        }
+
+        let mut stats = self.stats.lock();
+        let message = stats.messages.get_mut(&tracking.id).ok_or(Rejected::UnknownMessage)?;
+        //Un token se comparte entre todos los destinatarios, cada uno reporta cada evento una vez como mucho
+        let sent = message.counters.sent;
+        if *message.counters.count(event) >= sent {
+            return Err(Rejected::Exhausted);
+        }
+
+        let caller = message.caller.clone();
+        for entry in stats.entries(tracking, &caller, now_millis()) {
+            *entry.counters.count(event) += 1;
+        }
+        self.dirty.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    ///Solo las de mensajes, templates y topicos de caller
+    pub fn get(&self, kind: StatsKind, caller: &str, id: &str) -> Option<Counters> {
+        let stats = self.stats.lock();
+        let entries = match kind {
+            StatsKind::Messages => stats.messages.get(id).filter(|s| s.caller == caller),
+            StatsKind::Templates => stats.templates.get(caller).and_then(|owned| owned.get(id)),
+            StatsKind::Topics => stats.topics.get(caller).and_then(|owned| owned.get(id)),
+        };
+        entries.map(|s| s.counters.clone())
+    }
+
+    ///Borra lo vencido y guarda si hubo cambios. Se escribe una copia para no bloquear los eventos mientras tanto
+    fn flush(&self) {
+        let oldest = now_millis().saturating_sub(self.retention);
+        let copy = {
+            let mut stats = self.stats.lock();
+            let before = stats.len();
+            stats.retain(oldest);
+            let pruned = before != stats.len();
+            if !self.dirty.swap(false, Ordering::Relaxed) && !pruned {
+                return;
+            }
+            stats.clone()
+        };
+        self.stats.write(&copy);
+    }
+}
+
+///La clave VAPID firma los JWT de los push services, los tokens usan una clave derivada en vez de la misma
+fn derive_key(private_key: &str) -> [u8; 32] {
+    let mut key = [0; 32];
+    Hkdf::<Sha256>::new(None, private_key.as_bytes()).expand(KEY_INFO, &mut key).unwrap();
+    key
+}
+
+///Guarda los eventos periodicamente
+pub async fn run(state: Arc<AppState>) {
+    trace!("Events worker started");
+    loop {
+        tokio::time::sleep(FLUSH_EVERY).await;
+        let events = state.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || events.events.flush()).await {
+            tracing::error!("Events couldn't be saved: {}", e);
+        }
+    }
+}
+
+///Agrega el seguimiento a data. track ya descarto las notificaciones con data que no es un objeto
+pub fn embed(data: Option<Value>, tracking: &Tracking) -> Option<Value> {
+    let tracking = serde_json::to_value(tracking).unwrap();
+    match data {
+        None => Some(json!({"tracking": tracking})),
+        Some(Value::Object(mut fields)) => {
+            fields.insert("tracking".to_owned(), tracking);
+            Some(Value::Object(fields))
+        },
+        other => other,
+    }
+}
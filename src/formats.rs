@@ -0,0 +1,93 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use utoipa::ToSchema;
+
+use crate::routes::notify::{Dir, Notification};
+
+///Shape of the json sent to the service worker
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all="camelCase")]
+pub enum PayloadFormat {
+    ///`{"notification": ...}` with actions adapted to Angular's onActionClick. https://angular.dev/ecosystem/service-workers/push-notifications
+    #[default]
+    Angular,
+    ///`{"notification": ...}` with the fields exactly as they were received
+    Passthrough,
+    ///Declarative Web Push, `{"web_push": 8030, "notification": {..., "navigate": ...}}`. Shown by the browser without a service worker
+    Declarative,
+}
+
+///Notificacion tal como llego, sin los campos vacios
+pub fn passthrough(notification: Notification) -> Value {
+    let mut notification = serde_json::to_value(notification).unwrap();
+    if let Value::Object(fields) = &mut notification {
+        fields.retain(|_, v| !v.is_null());
+    }
+    json!({"notification": notification})
+}
+
+#[derive(Serialize)]
+struct DeclarativePush {
+    web_push    : u16,
+    notification: DeclarativeNotification,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all="camelCase")]
+struct DeclarativeNotification {
+    title              : String,
+    ///Url abierta al hacer click. Sale de la action "default"
+    navigate           : String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body               : Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dir                : Option<Dir>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon               : Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lang               : Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    require_interaction: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    silent             : Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag                : Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp          : Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data               : Option<Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    actions            : Vec<DeclarativeAction>,
+}
+
+#[derive(Serialize)]
+struct DeclarativeAction {
+    action  : String,
+    title   : String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon    : Option<String>,
+    navigate: String,
+}
+
+///Formato declarativo. Sin action "default" navega a la raiz del sitio
+pub fn declarative(notification: Notification) -> Value {
+    let Notification { title, body, dir, data, icon, lang, require_interaction, silent, tag, timestamp, actions, .. } = notification;
+
+    let mut navigate = "/".to_owned();
+    let mut declarative_actions = Vec::new();
+    //Mismos ids que en el formato de Angular
+    for (i, action) in actions.into_iter().flatten().enumerate() {
+        let id = action.id(i);
+        let url = action.url.unwrap_or_else(|| "/".to_owned());
+        if id == "default" {
+            navigate = url;
+        } else {
+            declarative_actions.push(DeclarativeAction { action: id, title: action.title, icon: action.icon, navigate: url });
+        }
+    }
+
+    serde_json::to_value(DeclarativePush {
+        web_push: 8030,
+        notification: DeclarativeNotification { title, navigate, body, dir, icon, lang, require_interaction, silent, tag, timestamp, data, actions: declarative_actions },
+    }).unwrap()
+}
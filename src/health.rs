@@ -0,0 +1,115 @@
+use std::{collections::VecDeque, sync::{Mutex, atomic::{AtomicBool, Ordering}}, time::{Duration, Instant}};
+
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use openssl::{bn::{BigNum, BigNumContext}, ec::{EcGroup, EcPoint, PointConversionForm}, nid::Nid};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::conf::{HealthConf, KeysJson};
+
+///Result of one readiness check
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone)]
+pub struct Check {
+    pub name  : String,
+    pub ok    : bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Check {
+    fn new(name: &str, result: Result<(), String>) -> Self {
+        Self { name: name.to_owned(), ok: result.is_ok(), detail: result.err() }
+    }
+}
+
+///Estado usado por /readyz
+pub struct Health {
+    conf     : HealthConf,
+    ///Se calcula una vez, las claves no cambian mientras corre el servidor
+    vapid    : Result<(), String>,
+    listening: AtomicBool,
+    ///Resultados recientes de los envios al push service
+    sends    : Mutex<VecDeque<(Instant, bool)>>,
+}
+
+impl Health {
+    pub fn new(conf: HealthConf, keys: &KeysJson) -> Self {
+        Self { conf, vapid: check_vapid(keys), listening: AtomicBool::new(false), sends: Mutex::new(VecDeque::new()) }
+    }
+
+    pub fn set_listening(&self) {
+        self.listening.store(true, Ordering::Relaxed);
+    }
+
+    pub fn record_send(&self, ok: bool) {
+        let now = Instant::now();
+        let mut sends = self.sends.lock().unwrap();
+        sends.push_back((now, ok));
+        prune(&mut sends, now, self.window());
+    }
+
+    fn window(&self) -> Duration {
+        Duration::from_secs(self.conf.window_secs)
+    }
+
+    ///Todos los chequeos de /readyz. La configuracion esta cargada si existe el estado
+    pub fn checks(&self) -> Vec<Check> {
+        let listening = if self.listening.load(Ordering::Relaxed) { Ok(()) } else { Err("listener not bound yet".to_owned()) };
+        vec![
+            Check::new("config", Ok(())),
+            Check::new("vapid_keys", self.vapid.clone()),
+            Check::new("listener", listening),
+            Check::new("push_success_rate", self.success_rate()),
+        ]
+    }
+
+    fn success_rate(&self) -> Result<(), String> {
+        let now = Instant::now();
+        let mut sends = self.sends.lock().unwrap();
+        prune(&mut sends, now, self.window());
+
+        //Con pocos envios un par de errores no dicen nada
+        if sends.len() < self.conf.min_samples {
+            return Ok(());
+        }
+        let ok = sends.iter().filter(|(_, ok)| *ok).count();
+        let rate = ok as f64 / sends.len() as f64;
+        if rate >= self.conf.min_success_rate {
+            Ok(())
+        } else {
+            Err(format!("{} of the last {} pushes succeeded ({:.0}%), minimum {:.0}%", ok, sends.len(), rate * 100.0, self.conf.min_success_rate * 100.0))
+        }
+    }
+}
+
+fn prune(sends: &mut VecDeque<(Instant, bool)>, now: Instant, window: Duration) {
+    while sends.front().is_some_and(|(t, _)| now.duration_since(*t) > window) {
+        sends.pop_front();
+    }
+}
+
+///La clave publica debe ser la que corresponde a la privada
+fn check_vapid(keys: &KeysJson) -> Result<(), String> {
+    let private = BASE64_URL_SAFE_NO_PAD.decode(&keys.private_key)
+        .map_err(|e| format!("private_key is not valid base64url: {e}"))?;
+    let public = BASE64_URL_SAFE_NO_PAD.decode(&keys.public_key)
+        .map_err(|e| format!("public_key is not valid base64url: {e}"))?;
+    if private.len() != 32 {
+        return Err("private_key must be 32 bytes".to_owned());
+    }
+
+    let derived = (|| -> Result<Vec<u8>, openssl::error::ErrorStack> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let mut ctx = BigNumContext::new()?;
+        let scalar = BigNum::from_slice(&private)?;
+        let mut point = EcPoint::new(&group)?;
+        point.mul_generator(&group, &scalar, &ctx)?;
+        point.to_bytes(&group, PointConversionForm::UNCOMPRESSED, &mut ctx)
+    })().map_err(|e| format!("private_key is not a valid P-256 key: {e}"))?;
+
+    if derived == public {
+        Ok(())
+    } else {
+        Err("public_key doesn't match private_key".to_owned())
+    }
+}
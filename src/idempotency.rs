@@ -0,0 +1,250 @@
+use std::{collections::HashMap, fs::{self, OpenOptions}, io::Write, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::Duration};
+
+use axum::{Json, body::Body, extract::{Request, State}, http::{HeaderValue, Method, StatusCode, header::CONTENT_TYPE}, middleware::Next, response::{IntoResponse, Response}};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{Instrument, Span, debug, info, trace};
+
+use crate::{auth::Caller, conf::data_path, scheduler::now_millis, state::AppState};
+
+const HEADER: &str = "Idempotency-Key";
+///Header agregado a las respuestas repetidas
+const REPLAYED_HEADER: &str = "Idempotent-Replayed";
+///Mismo limite que el extractor Json de axum
+const MAX_BODY: usize = 2 * 1024 * 1024;
+///Cada cuanto se guardan las respuestas nuevas
+const FLUSH_EVERY: Duration = Duration::from_secs(1);
+///Cada cuanto se borran las respuestas vencidas
+const PRUNE_EVERY: Duration = Duration::from_secs(10 * 60);
+
+///`fingerprint` es el hash del metodo y el body de la primera request
+#[derive(Deserialize, Serialize, Clone)]
+enum Entry {
+    ///La primera request todavia se esta procesando
+    InFlight { expires_at: u64, fingerprint: String },
+    Done { expires_at: u64, fingerprint: String, status: u16, body: String },
+}
+
+impl Entry {
+    fn expires_at(&self) -> u64 {
+        match self {
+            Entry::InFlight { expires_at, .. } | Entry::Done { expires_at, .. } => *expires_at,
+        }
+    }
+
+    fn matches(&self, request: &str) -> bool {
+        match self {
+            Entry::InFlight { fingerprint, .. } | Entry::Done { fingerprint, .. } => fingerprint == request,
+        }
+    }
+}
+
+///Una linea de idempotency.jsonl. Si una clave se repite vale la ultima
+#[derive(Deserialize, Serialize)]
+struct Stored {
+    key  : String,
+    entry: Entry,
+}
+
+///Respuestas de las requests con Idempotency-Key, persistidas en idempotency.jsonl junto al ejecutable.
+///Las respuestas nuevas se agregan al final, el archivo se reescribe solo al borrar las vencidas
+pub struct Idempotency {
+    path     : PathBuf,
+    entries  : Mutex<HashMap<String, Entry>>,
+    ///Respuestas todavia no escritas
+    pending  : Mutex<Vec<Stored>>,
+    ///Milisegundos que se recuerda cada respuesta
+    window_ms: u64,
+}
+
+impl Idempotency {
+    pub fn load(window_secs: u64) -> Self {
+        let path = data_path("idempotency.jsonl");
+        let now = now_millis();
+        let entries = read(&path).into_iter()
+            .filter(|s| s.entry.expires_at() > now)
+            .map(|s| (s.key, s.entry))
+            .collect();
+        Self { path, entries: Mutex::new(entries), pending: Mutex::new(Vec::new()), window_ms: window_secs * 1000 }
+    }
+
+    ///Devuelve la entrada vigente, o reserva la clave si no existe. Las vencidas se borran en prune
+    fn begin(&self, key: &str, fingerprint: &str) -> Option<Entry> {
+        let now = now_millis();
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get(key) && entry.expires_at() > now {
+            return Some(entry.clone());
+        }
+        //No se guarda a disco, si el proceso se cae la request se puede reintentar
+        entries.insert(key.to_owned(), Entry::InFlight { expires_at: now + self.window_ms, fingerprint: fingerprint.to_owned() });
+        None
+    }
+
+    fn finish(&self, key: &str, fingerprint: &str, result: Option<(u16, String)>) {
+        let mut entries = self.entries.lock().unwrap();
+        match result {
+            Some((status, body)) => {
+                let entry = Entry::Done { expires_at: now_millis() + self.window_ms, fingerprint: fingerprint.to_owned(), status, body };
+                entries.insert(key.to_owned(), entry.clone());
+                self.pending.lock().unwrap().push(Stored { key: key.to_owned(), entry });
+            },
+            //Solo se saca la reserva, que no se persiste
+            None => {
+                entries.remove(key);
+            },
+        }
+    }
+
+    ///Agrega al archivo las respuestas nuevas
+    fn flush(&self) {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        if pending.is_empty() {
+            return;
+        }
+        let text: String = pending.iter().map(|s| serde_json::to_string(s).unwrap() + "\n").collect();
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(text.as_bytes()));
+        if let Err(e) = result {
+            tracing::error!("{} couldn't be written: {}", self.path.display(), e);
+        }
+    }
+
+    ///Borra las vencidas y reescribe el archivo con las que quedan. Se escribe una copia para no bloquear las requests
+    fn prune(&self) {
+        let now = now_millis();
+        let done: Vec<Stored> = {
+            let mut entries = self.entries.lock().unwrap();
+            let before = entries.len();
+            entries.retain(|_, e| e.expires_at() > now);
+            if entries.len() == before {
+                return;
+            }
+            entries.iter()
+                .filter(|(_, e)| matches!(e, Entry::Done { .. }))
+                .map(|(k, e)| Stored { key: k.clone(), entry: e.clone() })
+                .collect()
+        };
+
+        let text: String = done.iter().map(|s| serde_json::to_string(s).unwrap() + "\n").collect();
+        let tmp = self.path.with_extension("jsonl.tmp");
+        if let Err(e) = fs::write(&tmp, text).and_then(|_| fs::rename(&tmp, &self.path)) {
+            tracing::error!("{} couldn't be pruned: {}", self.path.display(), e);
+        }
+    }
+}
+
+///Las lineas a medio escribir se ignoran
+fn read(path: &Path) -> Vec<Stored> {
+    match fs::read_to_string(path) {
+        Ok(text) => text.lines().filter_map(|line| serde_json::from_str(line).ok()).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+///Escribe las respuestas nuevas y borra las vencidas periodicamente. Es el unico que modifica el archivo
+pub async fn run(state: Arc<AppState>) {
+    trace!("Idempotency worker started");
+    let mut flush = tokio::time::interval(FLUSH_EVERY);
+    let mut prune = tokio::time::interval(PRUNE_EVERY);
+    loop {
+        let task: fn(&Idempotency) = tokio::select! {
+            _ = flush.tick() => Idempotency::flush,
+            _ = prune.tick() => Idempotency::prune,
+        };
+        let idempotency = state.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || task(&idempotency.idempotency)).await {
+            tracing::error!("Idempotency keys couldn't be saved: {}", e);
+        }
+    }
+}
+
+///Repite la respuesta de la primera request con el mismo Idempotency-Key en vez de volver a procesarla.
+///Los errores 5xx y 429 no se guardan, para poder reintentar
+pub async fn idempotency(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next
+) -> Response {
+    let key = req.headers()
+        .get(HEADER)
+        .and_then(|header| header.to_str().ok());
+
+    let key = match key {
+        Some(key) if req.method() == Method::POST => {
+            //Cada api key tiene su propio espacio de claves
+            let caller = req.extensions().get::<Caller>().map(|c| c.0.as_str()).unwrap_or_default();
+            format!("{}:{}:{}", caller, req.uri().path(), key)
+        },
+        _ => return next.run(req).await,
+    };
+
+    //El body se lee para comparar los reintentos con la primera request
+    let (parts, body) = req.into_parts();
+    let bytes = match axum::body::to_bytes(body, MAX_BODY).await {
+        Ok(b) => b,
+        Err(_) => return (StatusCode::PAYLOAD_TOO_LARGE, Json("Request body is too large")).into_response(),
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(parts.method.as_str());
+    hasher.update(b"\0");
+    hasher.update(&bytes);
+    let fingerprint = format!("{:x}", hasher.finalize());
+    let req = Request::from_parts(parts, Body::from(bytes));
+
+    match state.idempotency.begin(&key, &fingerprint) {
+        Some(entry) if !entry.matches(&fingerprint) => {
+            info!("{} {} reused with a different request", HEADER, key);
+            return (StatusCode::UNPROCESSABLE_ENTITY, Json("This Idempotency-Key was used with a different request")).into_response();
+        },
+        Some(Entry::Done { status, body, .. }) => {
+            info!("Replaying response for {} {}", HEADER, key);
+            let mut response = (StatusCode::from_u16(status).unwrap_or(StatusCode::OK), body).into_response();
+            response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            response.headers_mut().insert(REPLAYED_HEADER, HeaderValue::from_static("true"));
+            return response;
+        },
+        Some(Entry::InFlight { .. }) => {
+            info!("Request with {} {} is still being processed", HEADER, key);
+            return (StatusCode::CONFLICT, Json("A request with this Idempotency-Key is still being processed")).into_response();
+        },
+        None => {},
+    }
+    //En otra tarea, asi termina y se guarda aunque el cliente se desconecte: un reintento despues de un timeout
+    //recibe la respuesta de esta request en vez de enviar de nuevo
+    let processing = process(state.clone(), key.clone(), fingerprint.clone(), req, next);
+    match tokio::spawn(processing.instrument(Span::current())).await {
+        Ok(response) => response,
+        Err(e) => {
+            tracing::error!("Request with {} {} failed: {}", HEADER, key, e);
+            state.idempotency.finish(&key, &fingerprint, None);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json("Internal Server Error")).into_response()
+        },
+    }
+}
+
+///Procesa la request y guarda la respuesta
+async fn process(state: Arc<AppState>, key: String, fingerprint: String, req: Request, next: Next) -> Response {
+    let response = next.run(req).await;
+    let status = response.status();
+    if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+        state.idempotency.finish(&key, &fingerprint, None);
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(b) => b,
+        Err(e) => {
+            tracing::error!("Response body couldn't be read for {} {}: {}", HEADER, key, e);
+            state.idempotency.finish(&key, &fingerprint, None);
+            return Response::from_parts(parts, Body::empty());
+        },
+    };
+
+    debug!("Storing response for {} {}", HEADER, key);
+    state.idempotency.finish(&key, &fingerprint, Some((status.as_u16(), String::from_utf8_lossy(&bytes).into_owned())));
+    Response::from_parts(parts, Body::from(bytes))
+}
@@ -1,51 +1,165 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 use axum::{Json, middleware};
 use tracing::{debug, trace};
 use utoipa_axum::router::OpenApiRouter;
-use crate::{auth::auth, conf::{ConfFile, load_conf_file}, routes::{get_public_key::*, notify::*}};
+use crate::{audit::Audit, auth::auth, conf::{ApiKey, ConfFile, load_conf_file}, cors::cors, events::Events, health::Health, idempotency::{Idempotency, idempotency}, metrics::{Metrics, metrics, track}, rate_limit::RateLimits, recurring::Recurring, request_id::request_id, routes::{audit::*, events::*, get_public_key::*, health::*, messages::*, notify::*, notify_raw::*, recurring::*, service_worker::*, templates::*, topics::*, webhooks::*}, scheduler::Scheduler, state::AppState, templates::Templates, topics::Topics, webhooks::Webhooks};
 
 
+pub mod allowlist;
+pub mod audit;
 pub mod auth;
 pub mod conf;
+pub mod cors;
+pub mod events;
+pub mod formats;
+pub mod health;
+pub mod idempotency;
+pub mod metrics;
+pub mod push;
+pub mod rate_limit;
+pub mod recurring;
+pub mod request_id;
 pub mod routes;
+pub mod scheduler;
+pub mod state;
+pub mod store;
+pub mod telemetry;
+pub mod templates;
+pub mod topics;
+pub mod validation;
+pub mod webhooks;
 
 #[cfg(windows)]
 mod windows_service;
 
-fn init_tokio(router: axum::Router, addr: String) -> anyhow::Result<()> {
-    tokio::runtime::Builder::new_multi_thread()
+fn init_tokio(router: axum::Router, addr: String, state: Arc<AppState>) -> anyhow::Result<()> {
+    let res = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .unwrap()
         .block_on(async {
-            run_server(router, addr).await
-        })
+            run_server(router, addr, state).await
+        });
+
+    //Fuera del runtime, el exporter OTLP usa un cliente bloqueante
+    telemetry::shutdown();
+    res
 }
 
 
-async fn run_server(router: axum::Router, addr: String) -> anyhow::Result<()> {
+async fn run_server(router: axum::Router, addr: String, state: Arc<AppState>) -> anyhow::Result<()> {
+    spawn_workers(state.clone());
+
     trace!("Starting axum server on {}", addr);
     let listener = tokio::net::TcpListener::bind(addr).await?;
+    state.health.set_listening();
     axum::serve(listener, router)
+    .with_graceful_shutdown(shutdown_signal())
     .await?;
 
+    //Los cambios del ultimo segundo todavia no los guardo el worker
+    let stores = state.clone();
+    tokio::task::spawn_blocking(move || store::flush_all(&stores)).await?;
+
     debug!("Axum server stopped.");
 
     Ok(())
 }
 
-fn init_server() -> (axum::Router, String) {
-    let ConfFile { openapi, keys, server } = load_conf_file();
+///Ctrl+C, o SIGTERM en unix, detienen el servidor para que se exporten los spans pendientes
+async fn shutdown_signal() {
+    #[cfg(unix)] {
+        let mut term = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).expect("SIGTERM handler couldn't be installed");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = term.recv() => {},
+        }
+    }
+    #[cfg(not(unix))] {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+///Tareas de fondo que viven mientras corre el servidor
+fn spawn_workers(state: Arc<AppState>) {
+    tokio::spawn(scheduler::run(state.clone()));
+    tokio::spawn(recurring::run(state.clone()));
+    tokio::spawn(audit::run(state.clone()));
+    tokio::spawn(idempotency::run(state.clone()));
+    tokio::spawn(store::run(state.clone()));
+    tokio::spawn(events::run(state));
+}
+
+fn init_server() -> (axum::Router, String, Arc<AppState>) {
+    let ConfFile { openapi, keys, server, templates, rate_limits, payload, endpoints, idempotency: idempotency_conf, metrics: metrics_conf, health, logging, audit, webhooks, events, service_worker, .. } = load_conf_file();
     
-    let state = Arc::new(keys);
-    let api_key = Arc::new(server.api_key);
+    let mut api_keys = vec![ApiKey { name: "default".to_owned(), key: server.api_key, rate_limit: None }];
+    api_keys.extend(server.api_keys);
+
+    let health = Health::new(health, &keys);
+    let events = Events::load(events, &keys);
+    let webhooks = Webhooks::load(webhooks, &endpoints);
+    //Con timeout, un push service colgado no frena a los workers, que envian de a uno
+    let push_client = allowlist::client(&endpoints, reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(endpoints.connect_timeout_secs))
+        .timeout(Duration::from_secs(endpoints.timeout_secs)));
+    let state = Arc::new(AppState {
+        keys,
+        api_keys,
+        rate_limits: RateLimits::new(rate_limits),
+        payload,
+        endpoints,
+        push_client,
+        scheduler  : Scheduler::load(),
+        recurring  : Recurring::load(),
+        topics     : Topics::load(),
+        templates  : Templates::load(templates.fallback_locales),
+        idempotency: Idempotency::load(idempotency_conf.window_secs),
+        metrics    : Metrics::new(metrics_conf),
+        health,
+        logging,
+        audit      : Audit::load(audit),
+        webhooks,
+        events,
+        service_worker,
+    });
         
     //Armar rutas y openapi
     let (mut router, mut api): (axum::Router, utoipa::openapi::OpenApi) = OpenApiRouter::new()
-        .routes(utoipa_axum::routes!(get_public_key))
         .routes(utoipa_axum::routes!(notify))
-        .with_state(state)
-        .route_layer(middleware::from_fn_with_state(api_key, auth))
+        .routes(utoipa_axum::routes!(notify_raw))
+        .routes(utoipa_axum::routes!(cancel_message))
+        .routes(utoipa_axum::routes!(create_campaign, list_campaigns))
+        .routes(utoipa_axum::routes!(get_campaign, update_campaign, delete_campaign))
+        .routes(utoipa_axum::routes!(list_topics))
+        .routes(utoipa_axum::routes!(attach_subscription, detach_subscription))
+        .routes(utoipa_axum::routes!(publish))
+        .routes(utoipa_axum::routes!(list_templates))
+        .routes(utoipa_axum::routes!(get_template, put_template, delete_template))
+        .routes(utoipa_axum::routes!(list_audit))
+        .routes(utoipa_axum::routes!(create_webhook, list_webhooks))
+        .routes(utoipa_axum::routes!(delete_webhook))
+        .routes(utoipa_axum::routes!(get_stats))
+        .with_state(state.clone())
+        .route_layer(middleware::from_fn_with_state(state.clone(), idempotency))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth))
+        .route_layer(middleware::from_fn_with_state(state.clone(), track))
+        //Probes del orquestador, sin api_key
+        .merge(OpenApiRouter::new()
+            .routes(utoipa_axum::routes!(healthz))
+            .routes(utoipa_axum::routes!(readyz))
+            .with_state(state.clone()))
+        //Llamadas de los navegadores. Los eventos se validan con el token de cada mensaje
+        .merge(OpenApiRouter::new()
+            .routes(utoipa_axum::routes!(get_public_key))
+            .routes(utoipa_axum::routes!(post_event))
+            .with_state(state.clone())
+            .route_layer(middleware::from_fn(cors)))
+        //Scripts para los sitios
+        .merge(OpenApiRouter::new()
+            .routes(utoipa_axum::routes!(sw_js))
+            .routes(utoipa_axum::routes!(client_js))
+            .with_state(state.clone()))
         .split_for_parts();
     
     //Trasladar configuracion a openapi
@@ -56,11 +170,13 @@ fn init_server() -> (axum::Router, String) {
     
     //agregar url de openapi a las rutas
     router = router
-        .route("/openapi.json", axum::routing::get(Json(api)));
+        .route("/openapi.json", axum::routing::get(Json(api)))
+        .route("/metrics", axum::routing::get(metrics).with_state(state.clone()))
+        .layer(middleware::from_fn(request_id));
 
     let addr:String = format!("{}:{}",server.accept_from, server.port);
 
-    (router, addr)
+    (router, addr, state)
 }
 
 
@@ -89,8 +205,8 @@ fn main() -> anyhow::Result<()> {
         }
 
         if args.contains(&"--console".into()) {
-            let (router, addr) = init_server();
-            return init_tokio(router, addr);
+            let (router, addr, state) = init_server();
+            return init_tokio(router, addr, state);
         }
 
         // Started by SCM (no args)
@@ -98,8 +214,8 @@ fn main() -> anyhow::Result<()> {
     }
 
     #[cfg(not(windows))] {
-        let (router, addr) = init_server();
-        return init_tokio(router, addr);
+        let (router, addr, state) = init_server();
+        return init_tokio(router, addr, state);
     }
 }
 
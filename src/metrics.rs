@@ -0,0 +1,123 @@
+use std::{collections::HashSet, sync::Arc, time::{Duration, Instant}};
+
+use axum::{extract::{MatchedPath, Request, State}, http::{HeaderMap, StatusCode, header::{AUTHORIZATION, CONTENT_TYPE}}, middleware::Next, response::{IntoResponse, Response}};
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use tracing::info;
+
+use crate::{conf::MetricsConf, state::AppState};
+
+///Contadores expuestos en /metrics con el formato de Prometheus
+pub struct Metrics {
+    registry     : Registry,
+    sent         : IntCounterVec,
+    failed       : IntCounterVec,
+    requests     : HistogramVec,
+    push_service : HistogramVec,
+    scheduled    : IntGauge,
+    subscriptions: IntGauge,
+    ///Bearer token requerido para leer /metrics
+    token        : Option<String>,
+}
+
+impl Metrics {
+    pub fn new(conf: MetricsConf) -> Self {
+        let registry = Registry::new();
+
+        let sent = IntCounterVec::new(
+            Opts::new("webpush_pushes_sent_total", "Pushes accepted by the push service"),
+            &["origin"],
+        ).unwrap();
+        let failed = IntCounterVec::new(
+            Opts::new("webpush_pushes_failed_total", "Pushes that couldn't be delivered to the push service"),
+            &["origin", "error"],
+        ).unwrap();
+        let requests = HistogramVec::new(
+            HistogramOpts::new("webpush_http_request_duration_seconds", "Time to answer api requests"),
+            &["method", "path", "status"],
+        ).unwrap();
+        let push_service = HistogramVec::new(
+            HistogramOpts::new("webpush_push_service_duration_seconds", "Response time of the push services"),
+            &["origin"],
+        ).unwrap();
+        let scheduled = IntGauge::new("webpush_scheduled_messages", "Scheduled messages waiting to be sent").unwrap();
+        let subscriptions = IntGauge::new("webpush_active_subscriptions", "Distinct subscriptions stored in topics and recurring campaigns").unwrap();
+
+        registry.register(Box::new(sent.clone())).unwrap();
+        registry.register(Box::new(failed.clone())).unwrap();
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(push_service.clone())).unwrap();
+        registry.register(Box::new(scheduled.clone())).unwrap();
+        registry.register(Box::new(subscriptions.clone())).unwrap();
+
+        Self { registry, sent, failed, requests, push_service, scheduled, subscriptions, token: conf.token }
+    }
+
+    pub fn push_sent(&self, origin: &str) {
+        self.sent.with_label_values(&[origin]).inc();
+    }
+
+    pub fn push_failed(&self, origin: &str, error: &str) {
+        self.failed.with_label_values(&[origin, error]).inc();
+    }
+
+    ///Tiempo de respuesta del push service, exitoso o no
+    pub fn push_service_time(&self, origin: &str, elapsed: Duration) {
+        self.push_service.with_label_values(&[origin]).observe(elapsed.as_secs_f64());
+    }
+
+    ///Actualiza los gauges y devuelve el texto para Prometheus
+    fn render(&self, state: &AppState) -> String {
+        self.scheduled.set(state.scheduler.pending() as i64);
+
+        let mut endpoints: HashSet<String> = state.topics.endpoints();
+        endpoints.extend(state.recurring.endpoints());
+        self.subscriptions.set(endpoints.len() as i64);
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+///Mide cuanto tarda cada ruta de la api. Usa el path de la ruta, no el de la request, para no crear una serie por id
+pub async fn track(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next
+) -> Response {
+    let method = req.method().to_string();
+    let path = req.extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_owned())
+        .unwrap_or_default();
+
+    let start = Instant::now();
+    let response = next.run(req).await;
+
+    state.metrics.requests
+        .with_label_values(&[method.as_str(), path.as_str(), response.status().as_str()])
+        .observe(start.elapsed().as_secs_f64());
+    response
+}
+
+///Prometheus metrics. Requires `Authorization: Bearer <metrics.token>` when a token is configured
+pub async fn metrics(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(token) = &state.metrics.token {
+        let authorized = headers.get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .is_some_and(|t| t == token);
+        if !authorized {
+            info!("StatusCode::UNAUTHORIZED Missing or wrong metrics token");
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    }
+
+    (
+        [(CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+        state.metrics.render(&state),
+    ).into_response()
+}
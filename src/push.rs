@@ -0,0 +1,284 @@
+use web_push::{ContentEncoding, SubscriptionInfo, VapidSignatureBuilder, WebPushError, WebPushMessage, WebPushMessageBuilder, request_builder};
+
+use std::{collections::HashMap, time::{Duration, Instant}};
+
+use futures_util::{StreamExt, stream};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+use utoipa::ToSchema;
+
+use crate::{allowlist, audit::{AuditEntry, SendContext}, events::Tracking, formats::PayloadFormat, scheduler::now_millis, routes::notify::Subscription, state::AppState};
+
+///Pushes de un envio a varias suscripciones (topicos y campañas) que se envian a la vez
+const SEND_CONCURRENCY: usize = 16;
+
+#[derive(Debug)]
+pub enum PushError {
+    ///The VAPID signature couldn't be built from the configured private key
+    Vapid(String),
+    ///The push service rejected the message or couldn't be reached
+    Send(WebPushError),
+    ///The subscription or its push service exceeded the configured rate limit
+    RateLimited(Duration),
+    ///The endpoint isn't an allowed push service
+    EndpointNotAllowed(String),
+}
+
+impl PushError {
+    ///The push service reported that the subscription no longer exists
+    pub fn is_expired(&self) -> bool {
+        matches!(self, PushError::Send(WebPushError::EndpointNotFound(_) | WebPushError::EndpointNotValid(_)))
+    }
+
+    ///The push may succeed if retried later: rate limits, push service errors and unreachable push services
+    pub fn is_transient(&self) -> bool {
+        match self {
+            PushError::RateLimited(_) => true,
+            PushError::Send(WebPushError::ServerError { .. } | WebPushError::Unspecified) => true,
+            PushError::Send(WebPushError::Other(info)) => info.code == 429,
+            _ => false,
+        }
+    }
+
+    ///Wait asked by the rate limit or by the Retry-After of the push service
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            PushError::RateLimited(wait) => Some(*wait),
+            PushError::Send(WebPushError::ServerError { retry_after, .. }) => *retry_after,
+            _ => None,
+        }
+    }
+
+    ///Error label used in metrics
+    pub fn class(&self) -> &'static str {
+        match self {
+            PushError::Vapid(_) => "vapid",
+            PushError::RateLimited(_) => "rate_limited",
+            PushError::EndpointNotAllowed(_) => "endpoint_not_allowed",
+            _ if self.is_expired() => "expired",
+            PushError::Send(WebPushError::Unauthorized(_)) => "unauthorized",
+            PushError::Send(WebPushError::BadRequest(_)) => "bad_request",
+            PushError::Send(WebPushError::PayloadTooLarge) => "payload_too_large",
+            PushError::Send(WebPushError::ServerError { .. }) => "server_error",
+            PushError::Send(_) => "other",
+        }
+    }
+}
+
+impl std::fmt::Display for PushError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PushError::Vapid(msg) => write!(f, "{}", msg),
+            PushError::Send(e)    => write!(f, "Failed to send push: {}", e),
+            PushError::RateLimited(wait) => write!(f, "Rate limited, retry after {:?}", wait),
+            PushError::EndpointNotAllowed(msg) => write!(f, "Endpoint not allowed: {}", msg),
+        }
+    }
+}
+
+///Igual que HyperWebPushClient pero con el cliente de allowlist::client, que se conecta solo a direcciones publicas
+async fn post(client: &reqwest::Client, message: WebPushMessage) -> Result<(), WebPushError> {
+    let request = request_builder::build_request::<Vec<u8>>(message);
+    let mut builder = client.post(request.uri().to_string());
+    for (name, value) in request.headers() {
+        builder = builder.header(name.as_str(), value.as_bytes());
+    }
+
+    let mut response = builder.body(request.into_body()).send().await.map_err(|e| {
+        tracing::debug!("Push service couldn't be reached: {}", e);
+        WebPushError::Unspecified
+    })?;
+    let retry_after = response.headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_secs);
+    let status = http::StatusCode::from_u16(response.status().as_u16()).map_err(|_| WebPushError::Unspecified)?;
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(|_| WebPushError::Unspecified)? {
+        body.extend_from_slice(&chunk);
+        if body.len() > MAX_RESPONSE_SIZE {
+            return Err(WebPushError::ResponseTooLarge);
+        }
+    }
+
+    match request_builder::parse_response(status, body) {
+        Err(WebPushError::ServerError { retry_after: None, info }) => Err(WebPushError::ServerError { retry_after, info }),
+        result => result,
+    }
+}
+
+///Resultado de un envio a varias suscripciones
+#[derive(Default)]
+pub struct Delivery {
+    pub sent   : u64,
+    pub failed : u64,
+    ///Endpoints que el push service informo como vencidos
+    pub expired: Vec<String>,
+}
+
+///Envia a las suscripciones de a SEND_CONCURRENCY a la vez, con el payload de su formato
+pub async fn send_all(state: &AppState, subscriptions: &[Subscription], payloads: &HashMap<PayloadFormat, String>, context: &SendContext, tracking: Option<&Tracking>) -> Delivery {
+    let mut delivery = Delivery::default();
+    let mut sends = stream::iter(subscriptions.iter().cloned())
+        .map(|sub| async move {
+            let sent = send(state, &sub, Some(payloads[&sub.format()].as_bytes()), context).await;
+            (sub, sent)
+        })
+        .buffer_unordered(SEND_CONCURRENCY);
+
+    while let Some((sub, sent)) = sends.next().await {
+        match sent {
+            Ok(_) => {
+                delivery.sent += 1;
+                //Por cada push, asi los primeros destinatarios ya pueden reportar eventos
+                if let Some(tracking) = tracking {
+                    state.events.sent(tracking, &context.caller, 1);
+                }
+            },
+            Err(e) if e.is_expired() => delivery.expired.push(sub.endpoint),
+            Err(e) => {
+                debug!("Push to {} failed: {}", origin(&sub.endpoint), e);
+                delivery.failed += 1;
+            },
+        }
+    }
+    delivery
+}
+
+///Scheme and host of the push service, ej: https://fcm.googleapis.com
+pub fn origin(endpoint: &str) -> &str {
+    let host_start = endpoint.find("://").map(|i| i + 3).unwrap_or(0);
+    match endpoint[host_start..].find('/') {
+        Some(i) => &endpoint[..host_start + i],
+        None => endpoint,
+    }
+}
+
+///Cabecera aes128gcm: salt(16) + record size(4) + largo del key id(1) + clave publica del servidor(65)
+const AES128GCM_HEADER: usize = 86;
+///Delimitador de padding(1) + tag de autenticacion(16)
+const AES128GCM_OVERHEAD: usize = 17;
+///Largo del padding(2) + tag de autenticacion(16). En aesgcm salt y clave van en cabeceras http
+const AESGCM_OVERHEAD: usize = 18;
+///Respuestas mas largas del push service se descartan, igual que en web-push
+const MAX_RESPONSE_SIZE: usize = 64 * 1024;
+
+///Content encoding of the push message, as listed in PushManager.supportedContentEncodings
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all="lowercase")]
+pub enum Encoding {
+    ///RFC 8291, supported by every current browser
+    #[default]
+    Aes128gcm,
+    ///Legacy draft encoding used by older browsers and WebViews
+    Aesgcm,
+}
+
+impl From<Encoding> for ContentEncoding {
+    fn from(value: Encoding) -> Self {
+        match value {
+            Encoding::Aes128gcm => ContentEncoding::Aes128Gcm,
+            Encoding::Aesgcm    => ContentEncoding::AesGcm,
+        }
+    }
+}
+
+///Size of the request body once the payload is encrypted
+pub fn encrypted_size(payload_len: usize, encoding: Encoding) -> usize {
+    match encoding {
+        Encoding::Aes128gcm => AES128GCM_HEADER + payload_len + AES128GCM_OVERHEAD,
+        Encoding::Aesgcm    => payload_len + AESGCM_OVERHEAD,
+    }
+}
+
+///Largest plaintext web-push encrypts, longer ones fail in HttpEce::encrypt
+pub const MAX_PLAINTEXT: usize = 3052;
+
+///Maximum encrypted size: the configured one, limited by the largest plaintext web-push accepts
+pub fn max_encrypted_size(max_size: usize, encoding: Encoding) -> usize {
+    max_size.min(encrypted_size(MAX_PLAINTEXT, encoding))
+}
+
+///Encrypts the payload and sends it to the subscription's push service. Without payload nothing is encrypted
+#[tracing::instrument(name = "push_send", skip_all, fields(origin = origin(&subscription.endpoint)))]
+pub async fn send(state: &AppState, subscription: &Subscription, payload: Option<&[u8]>, context: &SendContext) -> Result<(), PushError> {
+    let start = Instant::now();
+    let result = deliver(state, subscription, payload).await;
+
+    state.audit.record(&AuditEntry {
+        time      : now_millis(),
+        context   : context.clone(),
+        target    : subscription.endpoint.clone(),
+        status    : result.as_ref().err().map_or("sent", |e| e.class()).to_owned(),
+        latency_ms: start.elapsed().as_millis() as u64,
+    });
+    state.webhooks.dispatch(context, &subscription.endpoint, &result);
+
+    match &result {
+        Ok(_) => state.metrics.push_sent(origin(&subscription.endpoint)),
+        //No se usa el origin de endpoints rechazados, podrian ser cualquier host
+        Err(e @ PushError::EndpointNotAllowed(_)) => state.metrics.push_failed("not_allowed", e.class()),
+        Err(e) => state.metrics.push_failed(origin(&subscription.endpoint), e.class()),
+    }
+    //Solo cuentan las respuestas del push service. Una suscripcion vencida no es una falla del servicio
+    match &result {
+        Ok(_) => state.health.record_send(true),
+        Err(e @ PushError::Send(_)) if !e.is_expired() => state.health.record_send(false),
+        Err(_) => {},
+    }
+    result
+}
+
+async fn deliver(state: &AppState, subscription: &Subscription, payload: Option<&[u8]>) -> Result<(), PushError> {
+    //Las direcciones las verifica push_client al conectarse, sin otro lookup
+    allowlist::check_url(&state.endpoints, &subscription.endpoint)
+        .map_err(PushError::EndpointNotAllowed)?;
+
+    state.rate_limits
+        .check_target(&subscription.endpoint, origin(&subscription.endpoint))
+        .map_err(PushError::RateLimited)?;
+
+    let sub = SubscriptionInfo::new(
+        subscription.endpoint.as_str(),
+        subscription.keys.p256dh.as_str(),
+        subscription.keys.auth.as_str(),
+    );
+
+    // Build VAPID signature
+    let sig = match VapidSignatureBuilder::from_base64(&state.keys.private_key, &sub) {
+        Ok(b) => match b.build() {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::error!("Failed to build VAPID signature: {}", e);
+                return Err(PushError::Vapid("VAPID signature error".into()));
+            }
+        },
+        Err(e) => {
+            tracing::error!("Failed to parse VAPID PEM: {}", e);
+            return Err(PushError::Vapid("VAPID PEM parse error".into()));
+        }
+    };
+
+    // Create message builder and optional payload
+    let mut builder = WebPushMessageBuilder::new(&sub);
+    if let Some(payload) = payload {
+        builder.set_payload(subscription.content_encoding.into(), payload);
+    }
+    builder.set_vapid_signature(sig);
+
+    let message = builder.build().map_err(PushError::Send)?;
+
+    let start = Instant::now();
+    let result = post(&state.push_client, message).await;
+    state.metrics.push_service_time(origin(&subscription.endpoint), start.elapsed());
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            tracing::error!("Failed to send push: {}", e);
+            Err(PushError::Send(e))
+        }
+    }
+}
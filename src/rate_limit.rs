@@ -0,0 +1,188 @@
+use std::{collections::HashMap, sync::Mutex, time::{Duration, Instant}};
+
+use axum::{Json, http::{StatusCode, header::RETRY_AFTER}, response::{IntoResponse, Response}};
+use serde::{Deserialize, Serialize};
+
+use crate::conf::{ApiKey, RateLimitsConf};
+
+///Cantidad de buckets a partir de la cual se descartan los que estan llenos
+const PRUNE_AT: usize = 10_000;
+///Espera minima entre limpiezas, asi recorrer todos los buckets no se repite en cada push
+const PRUNE_EVERY: Duration = Duration::from_secs(60);
+
+///Token bucket: allows bursts of `burst` requests, refilled at `per_second`
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+pub struct Limit {
+    pub burst     : u32,
+    pub per_second: f64,
+}
+
+struct Bucket {
+    tokens : f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &Limit, now: Instant) {
+        self.tokens = (self.tokens + now.saturating_duration_since(self.updated).as_secs_f64() * limit.per_second).min(limit.burst as f64);
+        self.updated = now;
+    }
+
+    ///None si hay un token, sino cuanto falta para el proximo. Con per_second 0 o muy chico es Duration::MAX
+    fn wait(&self, limit: &Limit) -> Option<Duration> {
+        (self.tokens < 1.0).then(|| Duration::try_from_secs_f64((1.0 - self.tokens) / limit.per_second).unwrap_or(Duration::MAX))
+    }
+}
+
+///Buckets por clave (api key, endpoint u origen)
+struct BucketMap {
+    buckets: HashMap<String, Bucket>,
+    pruned : Instant,
+}
+
+impl BucketMap {
+    ///Bucket de la clave, recargado hasta now
+    fn bucket(&mut self, key: &str, limit: &Limit, now: Instant) -> &mut Bucket {
+        if self.buckets.len() >= PRUNE_AT && now.saturating_duration_since(self.pruned) >= PRUNE_EVERY {
+            self.buckets.retain(|_, b| b.tokens + now.saturating_duration_since(b.updated).as_secs_f64() * limit.per_second < limit.burst as f64);
+            self.pruned = now;
+        }
+
+        let bucket = self.buckets.entry(key.to_owned()).or_insert(Bucket { tokens: limit.burst as f64, updated: now });
+        bucket.refill(limit, now);
+        bucket
+    }
+}
+
+struct Buckets(Mutex<BucketMap>);
+
+impl Default for Buckets {
+    fn default() -> Self {
+        Self(Mutex::new(BucketMap { buckets: HashMap::new(), pruned: Instant::now() }))
+    }
+}
+
+impl Buckets {
+    ///Consume un token o devuelve cuanto falta para el proximo
+    fn take(&self, key: &str, limit: &Limit, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.0.lock().unwrap();
+        let bucket = buckets.bucket(key, limit, now);
+        match bucket.wait(limit) {
+            Some(wait) => Err(wait),
+            None => {
+                bucket.tokens -= 1.0;
+                Ok(())
+            },
+        }
+    }
+}
+
+pub struct RateLimits {
+    conf         : RateLimitsConf,
+    api_keys     : Buckets,
+    subscriptions: Buckets,
+    push_services: Buckets,
+}
+
+impl RateLimits {
+    pub fn new(conf: RateLimitsConf) -> Self {
+        Self { conf, api_keys: Buckets::default(), subscriptions: Buckets::default(), push_services: Buckets::default() }
+    }
+
+    ///Usa el limite propio de la api key, o el general si no tiene
+    pub fn check_api_key(&self, key: &ApiKey) -> Result<(), Duration> {
+        match key.rate_limit.as_ref().or(self.conf.api_key.as_ref()) {
+            Some(limit) => self.api_keys.take(&key.name, limit, Instant::now()),
+            None => Ok(()),
+        }
+    }
+
+    ///Limita por suscripcion (usuario final) y por servicio de push
+    pub fn check_target(&self, endpoint: &str, origin: &str) -> Result<(), Duration> {
+        self.check_target_at(endpoint, origin, Instant::now())
+    }
+
+    ///Solo consume si los dos buckets tienen token, un push rechazado no gasta el de la suscripcion
+    fn check_target_at(&self, endpoint: &str, origin: &str, now: Instant) -> Result<(), Duration> {
+        //Siempre en el mismo orden, subscriptions y despues push_services
+        let mut subscriptions = self.subscriptions.0.lock().unwrap();
+        let mut push_services = self.push_services.0.lock().unwrap();
+
+        let mut buckets = Vec::new();
+        if let Some(limit) = &self.conf.subscription {
+            buckets.push((subscriptions.bucket(endpoint, limit, now), limit));
+        }
+        if let Some(limit) = &self.conf.push_service {
+            buckets.push((push_services.bucket(origin, limit, now), limit));
+        }
+
+        if let Some(wait) = buckets.iter().filter_map(|(bucket, limit)| bucket.wait(limit)).max() {
+            return Err(wait);
+        }
+        for (bucket, _) in buckets {
+            bucket.tokens -= 1.0;
+        }
+        Ok(())
+    }
+}
+
+///429 con Retry-After en segundos
+pub fn too_many_requests(retry_after: Duration) -> Response {
+    let secs = retry_after.as_secs_f64().ceil().min(u32::MAX as f64) as u64;
+    (StatusCode::TOO_MANY_REQUESTS, [(RETRY_AFTER, secs.to_string())], Json("Too many requests")).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: Limit = Limit { burst: 2, per_second: 0.5 };
+
+    #[test]
+    fn allows_the_burst_and_then_waits_for_the_refill() {
+        let buckets = Buckets::default();
+        let now = Instant::now();
+
+        assert!(buckets.take("key", &LIMIT, now).is_ok());
+        assert!(buckets.take("key", &LIMIT, now).is_ok());
+        assert_eq!(buckets.take("key", &LIMIT, now), Err(Duration::from_secs(2)));
+        //Otra clave tiene su propio bucket
+        assert!(buckets.take("other", &LIMIT, now).is_ok());
+
+        assert_eq!(buckets.take("key", &LIMIT, now + Duration::from_secs(1)), Err(Duration::from_secs(1)));
+        assert!(buckets.take("key", &LIMIT, now + Duration::from_secs(2)).is_ok());
+        //La recarga no pasa de burst
+        let later = now + Duration::from_secs(3600);
+        assert!(buckets.take("key", &LIMIT, later).is_ok());
+        assert!(buckets.take("key", &LIMIT, later).is_ok());
+        assert!(buckets.take("key", &LIMIT, later).is_err());
+    }
+
+    #[test]
+    fn never_refills_without_rate() {
+        let buckets = Buckets::default();
+        let now = Instant::now();
+        for per_second in [0.0, 1e-300] {
+            let limit = Limit { burst: 1, per_second };
+            assert!(buckets.take(&per_second.to_string(), &limit, now).is_ok());
+            assert_eq!(buckets.take(&per_second.to_string(), &limit, now), Err(Duration::MAX));
+        }
+    }
+
+    #[test]
+    fn rejected_push_doesnt_spend_the_subscription_token() {
+        let limits = RateLimits::new(RateLimitsConf {
+            api_key     : None,
+            subscription: Some(Limit { burst: 1, per_second: 0.0 }),
+            push_service: Some(Limit { burst: 1, per_second: 1.0 }),
+        });
+        let now = Instant::now();
+
+        assert!(limits.check_target_at("https://fcm.googleapis.com/a", "https://fcm.googleapis.com", now).is_ok());
+        //El push service no tiene token: se rechaza sin gastar el de la suscripcion b
+        assert_eq!(limits.check_target_at("https://fcm.googleapis.com/b", "https://fcm.googleapis.com", now), Err(Duration::from_secs(1)));
+        assert!(limits.check_target_at("https://fcm.googleapis.com/b", "https://fcm.googleapis.com", now + Duration::from_secs(1)).is_ok());
+        //La suscripcion a ya gasto el suyo
+        assert_eq!(limits.check_target_at("https://fcm.googleapis.com/a", "https://fcm.googleapis.com", now + Duration::from_secs(5)), Err(Duration::MAX));
+    }
+}
@@ -0,0 +1,225 @@
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tracing::{info, trace};
+use utoipa::ToSchema;
+
+use crate::{allowlist, audit::SendContext, push, routes::notify::{Notification, PayLoad, PayloadTooLarge, Subscription, build_payloads}, scheduler::now_millis, state::AppState, store::JsonStore, validation::{self, FieldError}};
+
+///Tiempo maximo que duerme el worker si no hay campañas
+const IDLE_WAIT: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone)]
+pub struct CampaignRequest {
+    ///Cron expression with seconds: `sec min hour day_of_month month day_of_week [year]`. Ej: `0 0 8 * * *`
+    pub cron         : String,
+    ///IANA timezone used to evaluate the cron expression. Ej: `America/Argentina/Buenos_Aires`. Defaults to UTC
+    #[serde(default = "default_timezone")]
+    pub timezone     : String,
+    pub subscriptions: Vec<Subscription>,
+    pub notification : Notification,
+}
+
+fn default_timezone() -> String {
+    "UTC".to_owned()
+}
+
+///Por que se rechaza una campaña
+pub enum CampaignInvalid {
+    ///Todos los campos invalidos
+    Fields(Vec<FieldError>),
+    ///El payload no entra ni recortado, se rechaza ahora en vez de fallar en cada ejecucion
+    PayloadTooLarge(PayloadTooLarge),
+}
+
+impl CampaignRequest {
+    ///Revisa el tamaño del payload solo si los campos son validos
+    pub async fn validate(&self, state: &AppState) -> Result<(), CampaignInvalid> {
+        let endpoints = &state.endpoints;
+        let mut errors = Vec::new();
+        if let Err(e) = Schedule::from_str(&self.cron) {
+            errors.push(FieldError::new("cron", format!("is not a valid cron expression: {e}")));
+        }
+        if let Err(e) = Tz::from_str(&self.timezone) {
+            errors.push(FieldError::new("timezone", format!("is not a valid timezone: {e}")));
+        }
+        for (i, sub) in self.subscriptions.iter().enumerate() {
+            if let Err(msg) = allowlist::check(endpoints, &sub.endpoint).await {
+                errors.push(FieldError::new(format!("subscriptions[{i}].endpoint"), msg));
+            }
+            validation::subscription(sub, &format!("subscriptions[{i}]"), &mut errors);
+        }
+        validation::notification(&self.notification, "notification", &mut errors);
+        if !errors.is_empty() {
+            return Err(CampaignInvalid::Fields(errors));
+        }
+
+        //El id todavia no existe al crearla, uno nulo ocupa lo mismo en el seguimiento
+        let payload = PayLoad { notification: self.notification.clone() };
+        let tracking = state.events.track(&uuid::Uuid::nil().to_string(), None, None, &payload.notification);
+        build_payloads(&state.payload, &payload, &self.subscriptions, tracking.as_ref())
+            .map(|_| ())
+            .map_err(CampaignInvalid::PayloadTooLarge)
+    }
+}
+
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone)]
+pub struct Campaign {
+    pub id      : String,
+    ///Unix time in milliseconds of the last run, or of the creation if it never ran
+    pub last_run: u64,
+    ///Name of the api key that created it. Only that key can see and change it
+    #[serde(default)]
+    pub caller  : String,
+    #[serde(flatten)]
+    pub def     : CampaignRequest,
+}
+
+impl Campaign {
+    ///Proxima ejecucion posterior a last_run, en milisegundos
+    fn next_run(&self) -> Option<u64> {
+        let schedule = Schedule::from_str(&self.def.cron).ok()?;
+        let tz = Tz::from_str(&self.def.timezone).ok()?;
+        let from = DateTime::<Utc>::from_timestamp_millis(self.last_run as i64)?.with_timezone(&tz);
+        let next = schedule.after(&from).next()?;
+        next.timestamp_millis().try_into().ok()
+    }
+}
+
+///Campañas recurrentes, persistidas en recurring.json junto al ejecutable
+pub struct Recurring {
+    campaigns: JsonStore<Vec<Campaign>>,
+    wake     : Notify,
+}
+
+impl Recurring {
+    pub fn load() -> Self {
+        Self { campaigns: JsonStore::load("recurring.json"), wake: Notify::new() }
+    }
+
+    ///Guarda los cambios, lo llama el worker de store
+    pub fn flush(&self) {
+        self.campaigns.flush();
+    }
+
+    ///Campañas creadas por caller
+    pub fn list(&self, caller: &str) -> Vec<Campaign> {
+        self.campaigns.lock().iter().filter(|c| c.caller == caller).cloned().collect()
+    }
+
+    ///Endpoints de las campañas de todas las api keys
+    pub fn endpoints(&self) -> Vec<String> {
+        self.campaigns.lock()
+            .iter()
+            .flat_map(|c| c.def.subscriptions.iter().map(|s| s.endpoint.clone()))
+            .collect()
+    }
+
+    pub fn get(&self, id: &str, caller: &str) -> Option<Campaign> {
+        self.campaigns.lock().iter().find(|c| c.id == id && c.caller == caller).cloned()
+    }
+
+    pub fn create(&self, def: CampaignRequest, caller: &str) -> Campaign {
+        let campaign = Campaign { id: uuid::Uuid::new_v4().to_string(), last_run: now_millis(), caller: caller.to_owned(), def };
+        let mut campaigns = self.campaigns.lock();
+        campaigns.push(campaign.clone());
+        self.campaigns.changed();
+        drop(campaigns);
+
+        self.wake.notify_one();
+        campaign
+    }
+
+    ///Reemplaza la definicion. El proximo envio se calcula desde ahora
+    pub fn update(&self, id: &str, def: CampaignRequest, caller: &str) -> Option<Campaign> {
+        let mut campaigns = self.campaigns.lock();
+        let campaign = campaigns.iter_mut().find(|c| c.id == id && c.caller == caller)?;
+        campaign.def = def;
+        campaign.last_run = now_millis();
+        let updated = campaign.clone();
+        self.campaigns.changed();
+        drop(campaigns);
+
+        self.wake.notify_one();
+        Some(updated)
+    }
+
+    pub fn delete(&self, id: &str, caller: &str) -> bool {
+        let mut campaigns = self.campaigns.lock();
+        let before = campaigns.len();
+        campaigns.retain(|c| c.id != id || c.caller != caller);
+        let removed = campaigns.len() != before;
+        if removed {
+            self.campaigns.changed();
+        }
+        removed
+    }
+
+    ///Quita las suscripciones que el push service reporto como vencidas
+    fn prune(&self, id: &str, expired: &[String]) {
+        let mut campaigns = self.campaigns.lock();
+        let Some(campaign) = campaigns.iter_mut().find(|c| c.id == id) else {
+            return;
+        };
+        campaign.def.subscriptions.retain(|s| !expired.contains(&s.endpoint));
+        self.campaigns.changed();
+    }
+
+    ///Marca como ejecutadas las campañas vencidas y devuelve cuando vence la siguiente
+    fn take_due(&self, now: u64) -> (Vec<Campaign>, Option<u64>) {
+        let mut campaigns = self.campaigns.lock();
+        let mut due = Vec::new();
+        for campaign in campaigns.iter_mut() {
+            if campaign.next_run().is_some_and(|t| t <= now) {
+                campaign.last_run = now;
+                due.push(campaign.clone());
+            }
+        }
+        if !due.is_empty() {
+            self.campaigns.changed();
+        }
+        let next = campaigns.iter().filter_map(|c| c.next_run()).min();
+        (due, next)
+    }
+}
+
+///Envia las campañas recurrentes a medida que vencen
+pub async fn run(state: Arc<AppState>) {
+    trace!("Recurring worker started");
+    loop {
+        let now = now_millis();
+        let (due, next) = state.recurring.take_due(now);
+
+        for campaign in due {
+            let context = SendContext::new(&campaign.caller, None, Some(&campaign.def.notification.title));
+            let payload = PayLoad { notification: campaign.def.notification };
+            //Todas las ejecuciones de la campaña suman al mismo mensaje
+            let tracking = state.events.track(&campaign.id, None, None, &payload.notification);
+            let payloads = match build_payloads(&state.payload, &payload, &campaign.def.subscriptions, tracking.as_ref()) {
+                Ok(p) => p,
+                Err(size) => {
+                    tracing::error!("Recurring push {} is too large: {} bytes, max {}", campaign.id, size.size, size.max_size);
+                    continue;
+                }
+            };
+            let delivery = push::send_all(&state, &campaign.def.subscriptions, &payloads, &context, tracking.as_ref()).await;
+            info!("Recurring push {} sent to {} subscriptions, {} failed, {} expired", campaign.id, delivery.sent, delivery.failed, delivery.expired.len());
+            if !delivery.expired.is_empty() {
+                state.recurring.prune(&campaign.id, &delivery.expired);
+            }
+        }
+
+        let wait = next
+            .map(|t| Duration::from_millis(t.saturating_sub(now_millis())).min(IDLE_WAIT))
+            .unwrap_or(IDLE_WAIT);
+
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {},
+            _ = state.recurring.wake.notified() => {},
+        }
+    }
+}
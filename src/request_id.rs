@@ -0,0 +1,43 @@
+use axum::{extract::Request, http::HeaderValue, middleware::Next, response::Response};
+use tracing::Instrument;
+
+use crate::telemetry;
+
+///Header con el que se recibe y se devuelve el id
+pub const HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+    static CURRENT: String;
+}
+
+///Id de la request que se esta atendiendo. Vacio fuera de una request
+pub fn current() -> String {
+    CURRENT.try_with(|id| id.clone()).unwrap_or_default()
+}
+
+///Usa el X-Request-Id recibido o genera uno. Se agrega al span de la request, asi cada linea de log indica a que request pertenece, y se devuelve en la respuesta
+pub async fn request_id(
+    req: Request,
+    next: Next
+) -> Response {
+    let id = req.headers()
+        .get(HEADER)
+        .and_then(|header| header.to_str().ok())
+        .filter(|id| is_valid(id))
+        .map(str::to_owned)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    let span = tracing::info_span!("request", id = %id, method = %req.method(), path = %req.uri().path());
+    telemetry::set_parent(&span, req.headers());
+
+    let mut response = CURRENT.scope(id.clone(), next.run(req).instrument(span)).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(HEADER, value);
+    }
+    response
+}
+
+///Ids recibidos: ascii visible y de largo acotado, para que no ensucien los logs
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.chars().all(|c| c.is_ascii_graphic())
+}
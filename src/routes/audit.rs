@@ -0,0 +1,32 @@
+use std::sync::Arc;
+
+use axum::{Extension, Json, extract::{Query, State}, http::StatusCode, response::IntoResponse};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{audit::{AuditEntry, AuditQuery}, auth::Caller, state::AppState};
+
+#[derive(utoipa::IntoResponses,Deserialize,Serialize, ToSchema)]
+pub enum ListAuditResponses {
+    /// Matching entries, newest first
+    #[response(status = 200)]
+    Ok(Vec<AuditEntry>),
+}
+
+impl IntoResponse for ListAuditResponses {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            ListAuditResponses::Ok(entries) => (StatusCode::OK, Json(entries)).into_response(),
+        }
+    }
+}
+
+///Push attempts recorded in the audit log for the api key
+#[utoipa::path(get, path = "/audit", params(AuditQuery), responses(ListAuditResponses))]
+pub async fn list_audit(
+    State(state): State<Arc<AppState>>,
+    Extension(caller): Extension<Caller>,
+    Query(query): Query<AuditQuery>,
+) -> ListAuditResponses {
+    ListAuditResponses::Ok(state.audit.query(&caller.0, &query).await)
+}
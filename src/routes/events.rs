@@ -0,0 +1,114 @@
+use std::sync::Arc;
+
+use axum::{Extension, Json, extract::{Path, State}, http::StatusCode, response::IntoResponse};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+use utoipa::ToSchema;
+
+use crate::{auth::Caller, events::{Counters, Event, Rejected, StatsKind, Tracking}, state::AppState, validation::FieldError};
+
+///Body sent by the service worker: the `tracking` object of the notification data plus the event
+#[derive(Deserialize, Serialize, ToSchema, Debug)]
+pub struct EventReport {
+    #[serde(flatten)]
+    pub tracking: Tracking,
+    ///`shown`, `clicked`, `closed` or `action:<id>`
+    pub event   : String,
+}
+
+#[derive(utoipa::IntoResponses,Deserialize,Serialize, ToSchema)]
+pub enum EventResponses {
+    /// The event was counted
+    #[response(status = 200)]
+    Ok(String),
+
+    /// Every invalid field of the event
+    #[response(status = 400)]
+    BadRequest(Vec<FieldError>),
+
+    /// The token doesn't match the message
+    #[response(status = 403)]
+    Forbidden,
+
+    /// No push was sent with this message id, or its stats were removed by retention
+    #[response(status = 404)]
+    NotFound,
+
+    /// Every push of the message already reported this event
+    #[response(status = 409)]
+    Conflict(String),
+}
+
+impl IntoResponse for EventResponses {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            EventResponses::Ok(msg) => (StatusCode::OK, Json(msg)).into_response(),
+            EventResponses::BadRequest(errors) => (StatusCode::BAD_REQUEST, Json(errors)).into_response(),
+            EventResponses::Forbidden => (StatusCode::FORBIDDEN, Json("Forbidden")).into_response(),
+            EventResponses::NotFound => (StatusCode::NOT_FOUND, Json("Not Found")).into_response(),
+            EventResponses::Conflict(msg) => (StatusCode::CONFLICT, Json(msg)).into_response(),
+        }
+    }
+}
+
+#[derive(utoipa::IntoResponses,Deserialize,Serialize, ToSchema)]
+pub enum StatsResponses {
+    /// Events received
+    #[response(status = 200)]
+    Ok(Counters),
+
+    /// No events were received for it
+    #[response(status = 404)]
+    NotFound,
+}
+
+impl IntoResponse for StatsResponses {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            StatsResponses::Ok(counters) => (StatusCode::OK, Json(counters)).into_response(),
+            StatsResponses::NotFound => (StatusCode::NOT_FOUND, Json("Not Found")).into_response(),
+        }
+    }
+}
+
+///Counts an event of a notification. Called by the service worker, doesn't require api_key
+#[utoipa::path(post, path = "/events", responses(EventResponses))]
+pub async fn post_event(
+    State(state): State<Arc<AppState>>,
+    Json(report): Json<EventReport>,
+) -> EventResponses {
+    let event = match Event::parse(&report.event) {
+        Ok(event) => event,
+        Err(msg) => return EventResponses::BadRequest(vec![FieldError::new("event", msg)]),
+    };
+    if !state.events.verify(&report.tracking) {
+        info!("Event with invalid token for message {}", report.tracking.id);
+        return EventResponses::Forbidden;
+    }
+
+    match state.events.record(&report.tracking, &event) {
+        Ok(_) => {
+            debug!("Event {:?} recorded for message {}", event, report.tracking.id);
+            EventResponses::Ok("Event recorded".into())
+        },
+        Err(Rejected::UnknownAction) => EventResponses::BadRequest(vec![FieldError::new("event", "the notification doesn't have that action")]),
+        Err(Rejected::UnknownMessage) => EventResponses::NotFound,
+        Err(Rejected::Exhausted) => {
+            debug!("Event {:?} already reported by every push of message {}", event, report.tracking.id);
+            EventResponses::Conflict("Every push of the message already reported this event".into())
+        },
+    }
+}
+
+///Events of a message, a template or a topic sent by the api key
+#[utoipa::path(get, path = "/stats/{kind}/{id}", params(("kind" = StatsKind, Path), ("id" = String, Path)), responses(StatsResponses))]
+pub async fn get_stats(
+    State(state): State<Arc<AppState>>,
+    Extension(caller): Extension<Caller>,
+    Path((kind, id)): Path<(StatsKind, String)>,
+) -> StatsResponses {
+    match state.events.get(kind, &caller.0, &id) {
+        Some(counters) => StatsResponses::Ok(counters),
+        None => StatsResponses::NotFound,
+    }
+}
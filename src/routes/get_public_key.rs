@@ -4,7 +4,7 @@ use axum::{extract::State, http::StatusCode, response::IntoResponse};
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 
-use crate::conf::KeysJson;
+use crate::state::AppState;
 
 #[derive(utoipa::IntoResponses,Deserialize,Serialize, ToSchema)]
 pub enum GetPuKeyResponses {
@@ -23,7 +23,7 @@ impl IntoResponse for GetPuKeyResponses {
 
 #[utoipa::path(get, path = "/get_public_key", responses(GetPuKeyResponses))]
 pub async fn get_public_key(
-    State(conf): State<Arc<KeysJson>>,
+    State(state): State<Arc<AppState>>,
 ) -> GetPuKeyResponses {
-    return GetPuKeyResponses::Ok(conf.public_key.clone());
+    return GetPuKeyResponses::Ok(state.keys.public_key.clone());
 }
\ No newline at end of file
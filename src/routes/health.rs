@@ -0,0 +1,62 @@
+use std::sync::Arc;
+
+use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{health::Check, state::AppState};
+
+#[derive(utoipa::IntoResponses,Deserialize,Serialize, ToSchema)]
+pub enum HealthzResponses {
+    /// The process is alive
+    #[response(status = 200)]
+    Ok(String),
+}
+
+impl IntoResponse for HealthzResponses {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            HealthzResponses::Ok(msg) => (StatusCode::OK, Json(msg)).into_response(),
+        }
+    }
+}
+
+#[derive(utoipa::IntoResponses,Deserialize,Serialize, ToSchema)]
+pub enum ReadyzResponses {
+    /// Every check passed
+    #[response(status = 200)]
+    Ok(Vec<Check>),
+
+    /// Every check, failed ones with their detail
+    #[response(status = 503)]
+    ServiceUnavailable(Vec<Check>),
+}
+
+impl IntoResponse for ReadyzResponses {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            ReadyzResponses::Ok(checks) => (StatusCode::OK, Json(checks)).into_response(),
+            ReadyzResponses::ServiceUnavailable(checks) => (StatusCode::SERVICE_UNAVAILABLE, Json(checks)).into_response(),
+        }
+    }
+}
+
+///Liveness probe. Doesn't require api_key
+#[utoipa::path(get, path = "/healthz", responses(HealthzResponses))]
+pub async fn healthz() -> HealthzResponses {
+    HealthzResponses::Ok("ok".into())
+}
+
+///Readiness probe: config loaded, VAPID key pair valid, listener bound and recent push success rate. Doesn't require api_key
+#[utoipa::path(get, path = "/readyz", responses(ReadyzResponses))]
+pub async fn readyz(
+    State(state): State<Arc<AppState>>,
+) -> ReadyzResponses {
+    let checks = state.health.checks();
+    if checks.iter().all(|c| c.ok) {
+        ReadyzResponses::Ok(checks)
+    } else {
+        tracing::warn!("Not ready: {:?}", checks.iter().filter(|c| !c.ok).collect::<Vec<_>>());
+        ReadyzResponses::ServiceUnavailable(checks)
+    }
+}
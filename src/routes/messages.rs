@@ -0,0 +1,43 @@
+use std::sync::Arc;
+
+use axum::{Extension, Json, extract::{Path, State}, http::StatusCode, response::IntoResponse};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use utoipa::ToSchema;
+
+use crate::{auth::Caller, state::AppState};
+
+#[derive(utoipa::IntoResponses,Deserialize,Serialize, ToSchema)]
+pub enum CancelMessageResponses {
+    /// The scheduled message was removed
+    #[response(status = 200)]
+    Ok(String),
+
+    /// There is no pending message with that id scheduled by the api key
+    #[response(status = 404)]
+    NotFound,
+}
+
+impl IntoResponse for CancelMessageResponses {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            CancelMessageResponses::Ok(msg) => (StatusCode::OK, Json(msg)).into_response(),
+            CancelMessageResponses::NotFound => (StatusCode::NOT_FOUND, Json("Not Found")).into_response(),
+        }
+    }
+}
+
+///Cancels a notification scheduled with send_at by the same api key
+#[utoipa::path(delete, path = "/messages/{id}", params(("id" = String, Path, description = "Id returned when the notification was scheduled")), responses(CancelMessageResponses))]
+pub async fn cancel_message(
+    State(state): State<Arc<AppState>>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> CancelMessageResponses {
+    if state.scheduler.cancel(&id, &caller.0) {
+        info!("Scheduled push {} cancelled", id);
+        CancelMessageResponses::Ok("Message cancelled".into())
+    } else {
+        CancelMessageResponses::NotFound
+    }
+}
@@ -1,2 +1,12 @@
 pub mod notify;
-pub mod get_public_key;
\ No newline at end of file
+pub mod notify_raw;
+pub mod get_public_key;
+pub mod messages;
+pub mod recurring;
+pub mod templates;
+pub mod topics;
+pub mod health;
+pub mod audit;
+pub mod webhooks;
+pub mod events;
+pub mod service_worker;
@@ -1,83 +1,188 @@
-use std::sync::Arc;
+use std::{collections::{HashMap, hash_map::Entry}, sync::Arc, time::Duration};
 
-use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
-use chrono::Utc;
+use axum::{Extension, Json, extract::State, http::StatusCode, response::IntoResponse};
 use serde::{Deserialize, Serialize};
-use serde_json::{Value, json};
+use serde_json::{Map, Value, json};
 use tracing::info;
 use utoipa::ToSchema;
-use web_push::{ContentEncoding, HyperWebPushClient, SubscriptionInfo, VapidSignatureBuilder, WebPushClient, WebPushMessageBuilder};
 
-use crate::conf::KeysJson;
+use crate::{allowlist, audit::SendContext, auth::Caller, conf::{PayloadConf, TrimField}, events::{self, Tracking}, formats::{self, PayloadFormat}, push::{self, Encoding, PushError}, rate_limit::too_many_requests, request_id, scheduler::now_millis, state::AppState, templates::TemplateRef, validation::{self, FieldError}};
 
-#[derive(Deserialize, ToSchema, Debug)]
-struct SubscriptionKeys {
-    p256dh: String,
-    auth  : String,
+#[derive(Deserialize, ToSchema, Serialize, Clone)]
+pub struct SubscriptionKeys {
+    pub p256dh: String,
+    pub auth  : String,
 }
 
-#[derive(Deserialize, ToSchema, Debug)]
-struct Subscription {
-    endpoint: String,
-    keys    : SubscriptionKeys,
+///Las claves permiten descifrar los mensajes, nunca se muestran
+impl std::fmt::Debug for SubscriptionKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SubscriptionKeys")
+            .field("p256dh", &"<redacted>")
+            .field("auth", &"<redacted>")
+            .finish()
+    }
+}
+
+#[derive(Deserialize, ToSchema, Serialize, Clone)]
+pub struct Subscription {
+    pub endpoint        : String,
+    pub keys            : SubscriptionKeys,
+    ///One of PushManager.supportedContentEncodings. Defaults to aes128gcm
+    #[serde(default)]
+    pub content_encoding: Encoding,
+    ///Payload shape expected by this subscription's service worker. Defaults to angular
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format          : Option<PayloadFormat>,
+    ///What the browser can show, recorded when subscribing. Used to warn about ignored fields
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capabilities    : Option<Capabilities>,
+}
+
+#[derive(Deserialize, ToSchema, Debug, Serialize, Clone, Default)]
+pub struct Capabilities {
+    ///Notification.maxActions of the browser
+    pub max_actions    : Option<usize>,
+    ///False if the browser doesn't show the image field
+    pub supports_images: Option<bool>,
+}
+
+///El endpoint identifica al usuario, solo se muestra el origin del push service
+impl std::fmt::Debug for Subscription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Subscription")
+            .field("endpoint", &push::origin(&self.endpoint))
+            .field("keys", &self.keys)
+            .field("content_encoding", &self.content_encoding)
+            .field("format", &self.format)
+            .field("capabilities", &self.capabilities)
+            .finish()
+    }
+}
+
+impl Subscription {
+    pub fn format(&self) -> PayloadFormat {
+        self.format.unwrap_or_default()
+    }
 }
 
-#[derive(Deserialize, ToSchema, Debug)]
+#[derive(Deserialize, ToSchema, Debug, Serialize, Clone)]
 pub struct NotificationRequest {
-    subscription: Subscription,
-    payload     : PayLoad,
+    pub subscription: Subscription,
+    ///Required unless template is set or mode is empty
+    pub payload     : Option<PayLoad>,
+    ///Builds the notification from a stored template instead of payload
+    pub template    : Option<TemplateRef>,
+    ///Unix time in milliseconds. If it's in the future the notification is stored and sent when due
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub send_at     : Option<u64>,
+    ///Overrides the format of the subscription
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format      : Option<PayloadFormat>,
+    #[serde(default)]
+    pub mode        : NotifyMode,
+}
+
+#[derive(Deserialize, ToSchema, Debug, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all="camelCase")]
+pub enum NotifyMode {
+    ///Sends the notification built from payload or template
+    #[default]
+    Notification,
+    ///Sends a push without payload. It only wakes the service worker, which gets a push event with no data
+    Empty,
 }
 
-#[derive(Deserialize, ToSchema, Debug, Serialize)]
-struct PayLoad {
-    notification: Notification,
+#[derive(Deserialize, ToSchema, Debug, Serialize, Clone)]
+pub struct PayLoad {
+    pub notification: Notification,
 }
 
 
 ///https://developer.mozilla.org/en-US/docs/Web/API/Notification#Instance_properties
-#[derive(Deserialize, ToSchema, Debug, Serialize)]
+#[derive(Deserialize, ToSchema, Debug, Serialize, Clone, Default)]
 #[serde(rename_all="camelCase")]
-struct Notification {
+pub struct Notification {
     ///The title of the notification
-    title              : String,
+    pub title              : String,
     ///A string containing the URL of an image to represent the notification when there is not enough space to display the notification itself
-    badge              : Option<String>,
+    pub badge              : Option<String>,
     ///The body string of the notification 
-    body               : Option<String>,
+    pub body               : Option<String>,
+    ///Text direction of title and body
+    pub dir                : Option<Dir>,
     ///Json data to be used by the application
-    data               : Option<Value>,
+    pub data               : Option<Value>,
     ///The URL of the image used as an icon of the notification
-    icon               : Option<String>,
+    pub icon               : Option<String>,
     ///The URL of an image to be displayed as part of the notification
-    image              : Option<String>,
+    pub image              : Option<String>,
     ///https://developer.mozilla.org/en-US/docs/Glossary/BCP_47_language_tag
-    lang               : Option<String>,
+    pub lang               : Option<String>,
     ///Specifies whether the user should be notified after a new notification replaces an old one.
-    renotify           : Option<bool>,
+    pub renotify           : Option<bool>,
     ///Prevent the notification from autoclosing without user interaction
-    require_interaction: Option<bool>,
+    pub require_interaction: Option<bool>,
     ///Prevent the notification from making noices or vibrations
-    silent             : Option<bool>,
+    pub silent             : Option<bool>,
     ///Groups notificactions and allows to replace them
-    tag                : Option<String>,
+    pub tag                : Option<String>,
     ///Unix time in milliseconds. It defaults to the current time
-    timestamp          : Option<u64>,
+    pub timestamp          : Option<u64>,
     ///https://developer.mozilla.org/en-US/docs/Web/API/Vibration_API#vibration_patterns
-    vibrate            : Option<Vec<u16>>,
+    pub vibrate            : Option<Vec<u16>>,
     ///https://angular.dev/ecosystem/service-workers/push-notifications
     /// Use 'default' as title to set the default action
-    actions            : Option<Vec<Action>>,
+    pub actions            : Option<Vec<Action>>,
+}
+
+#[derive(Deserialize, ToSchema, Debug, Serialize, Clone, Copy)]
+#[serde(rename_all="lowercase")]
+pub enum Dir {
+    Auto,
+    Ltr,
+    Rtl,
+}
+
+#[derive(Deserialize, ToSchema, Debug, Serialize, Clone)]
+pub struct Action {
+    ///Id received by the service worker. Defaults to `A1`, `A2`... by position, or `default` when the title is 'default'
+    pub action   : Option<String>,
+    pub title    : String,
+    ///URL of the icon shown in the action button
+    pub icon     : Option<String>,
+    pub operation: Operation,
+    ///Relative or absolute URL. Angular uses the service worker origin when it's missing
+    pub url      : Option<String>,
+    ///Json data stored with the action in onActionClick
+    pub data     : Option<Value>,
+}
+
+impl Notification {
+    ///Ids de las actions que se muestran como botones. La action default no lleva boton
+    pub fn action_ids(&self) -> Vec<String> {
+        self.actions.iter().flatten()
+            .enumerate()
+            .map(|(i, a)| a.id(i))
+            .filter(|id| id != "default")
+            .collect()
+    }
 }
 
-#[derive(Deserialize, ToSchema, Debug, Serialize)]
-struct Action {
-    title    : String,
-    operation: Operation,
-    url      : String,
+impl Action {
+    ///Id de la action. Sin id explicito se numera por posicion (base 1)
+    pub fn id(&self, index: usize) -> String {
+        match &self.action {
+            Some(action) => action.clone(),
+            None if self.title == "default" => "default".to_owned(),
+            None => format!("A{}", index + 1),
+        }
+    }
 }
 
 ///Copia de Notification pero con las actions adaptadas
 #[derive(Serialize, Debug)]
+#[serde(rename_all="camelCase")]
 struct NotifPush {
     title              : String,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -85,6 +190,8 @@ struct NotifPush {
     #[serde(skip_serializing_if = "Option::is_none")]
     body               : Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    dir                : Option<Dir>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     data               : Option<Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     icon               : Option<String>,
@@ -111,151 +218,450 @@ struct NotifPush {
 #[derive(Serialize, Debug)]
 struct ActionPush {
     action: String,
-    title : String
+    title : String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon  : Option<String>,
 }
 
 impl From<Notification> for NotifPush {
     fn from(value: Notification) -> Self {
-        let Notification{ title, badge, body, data, icon, image, lang, renotify, require_interaction, silent, tag, timestamp, vibrate, actions } = value;
-        let mut on_action_click = json!({});
-        let mut count = 0;
-        let mut d:Option<Value> = None;
-        
-        let a = actions.map(|val| {
-            let actions: Vec<ActionPush> = val.into_iter().map(|row|{
-                count +=1;
-
-                let mut ret = ActionPush { action: format!("A{count}"), title:row.title };
-                
-                if ret.title == "default" {
-                    ret.action = "default".to_owned();
-                }
-                
-                let a = json!({"operation": row.operation, "url": row.url});
-                on_action_click[ret.action.clone()] = a;
+        let Notification{ title, badge, body, dir, data, icon, image, lang, renotify, require_interaction, silent, tag, timestamp, vibrate, actions } = value;
+        let mut on_action_click = Map::new();
+        let mut push_actions = Vec::new();
 
-                ret
-            })
-            .collect();
-            
-            if actions.len() >0 {
-                d = match data {
-                    Some(mut data) => {
-                        data["onActionClick"] = on_action_click;
-                        Some(data)
-                    },
-                    None => {
-                        Some(json!({"onActionClick":on_action_click}))
-                    },
-                };
+        for (i, row) in actions.into_iter().flatten().enumerate() {
+            let id = row.id(i);
+
+            let mut click = json!({"operation": row.operation});
+            if let Some(url) = row.url {
+                click["url"] = url.into();
+            }
+            if let Some(data) = row.data {
+                click["data"] = data;
             }
-            
+            on_action_click.insert(id.clone(), click);
 
-            actions
-            .into_iter()
-            .filter(|row| row.action != "default")
-            .collect()
-        })
-        //convertir a None si array vacio
-        .and_then(|a: Vec<ActionPush>| if a.is_empty() {None} else {Some(a)});
+            //La action default se ejecuta al hacer click en la notificacion, no lleva boton
+            if id != "default" {
+                push_actions.push(ActionPush { action: id, title: row.title, icon: row.icon });
+            }
+        }
 
+        let data = if on_action_click.is_empty() {
+            data
+        } else {
+            let mut data = data.unwrap_or_else(|| json!({}));
+            data["onActionClick"] = Value::Object(on_action_click);
+            Some(data)
+        };
 
-        Self { title, badge, body, data:d, icon, image, lang, renotify, require_interaction, silent, tag, timestamp, vibrate, actions:a}
+        //convertir a None si array vacio
+        let actions = if push_actions.is_empty() {None} else {Some(push_actions)};
+
+        Self { title, badge, body, dir, data, icon, image, lang, renotify, require_interaction, silent, tag, timestamp, vibrate, actions }
     }
 }
 
-#[derive(Deserialize, ToSchema, Debug, Serialize)]
+#[derive(Deserialize, ToSchema, Debug, Serialize, Clone)]
 #[serde(rename_all="camelCase")]
-enum Operation {
+pub enum Operation {
     OpenWindow,
     FocusLastFocusedOrOpen,
     NavigateLastFocusedOrOpen,
     SendRequest,
 }
 
+///Serializes the payload in the shape the service worker expects
+fn serialize_payload(mut notification: Notification, format: PayloadFormat, tracking: Option<&Tracking>) -> String {
+    //Se agrega en cada serializacion, asi sobrevive al recorte de data y cuenta en el tamaño
+    if let Some(tracking) = tracking {
+        notification.data = events::embed(notification.data, tracking);
+    }
+    let payload = match format {
+        PayloadFormat::Angular => {
+            let notif_push: NotifPush = notification.into();
+            json!({"notification": notif_push})
+        },
+        PayloadFormat::Passthrough => formats::passthrough(notification),
+        PayloadFormat::Declarative => formats::declarative(notification),
+    };
+    serde_json::to_string(&payload).unwrap()
+}
+
+///Size of the payload once encrypted, compared to the configured maximum
+#[derive(Deserialize, Serialize, ToSchema, Debug)]
+pub struct PayloadTooLarge {
+    pub size    : usize,
+    pub max_size: usize,
+}
+
+///Serializes the payload, trimming the configured fields if it doesn't fit in the push service limit
+pub fn build_payload(conf: &PayloadConf, mut payload: PayLoad, encoding: Encoding, format: PayloadFormat, tracking: Option<&Tracking>) -> Result<String, PayloadTooLarge> {
+    if payload.notification.timestamp.is_none() {
+        payload.notification.timestamp = Some(now_millis())
+    }
+
+    let mut notification = payload.notification;
+    if notification.require_interaction.is_none() {
+        notification.require_interaction = conf.require_interaction;
+    }
+    let mut serialized = serialize_payload(notification.clone(), format, tracking);
+    let mut trim = conf.trim.iter();
+    let max_size = push::max_encrypted_size(conf.max_size, encoding);
+
+    while push::encrypted_size(serialized.len(), encoding) > max_size {
+        let Some(field) = trim.next() else {
+            return Err(PayloadTooLarge { size: push::encrypted_size(serialized.len(), encoding), max_size });
+        };
+
+        match field {
+            TrimField::Data => notification.data = None,
+            TrimField::Body => if let Some(body) = notification.body.take() {
+                //Busqueda binaria del largo de body mas grande que entra, porque el escapado json cambia el tamaño
+                let (mut fits, mut too_long) = (0, body.len());
+                while too_long - fits > 1 {
+                    let mut mid = (fits + too_long) / 2;
+                    while !body.is_char_boundary(mid) {
+                        mid -= 1;
+                    }
+                    if mid <= fits {
+                        break;
+                    }
+                    notification.body = Some(format!("{}{}", &body[..mid], TRIM_MARK));
+                    if push::encrypted_size(serialize_payload(notification.clone(), format, tracking).len(), encoding) <= max_size {
+                        fits = mid;
+                    } else {
+                        too_long = mid;
+                    }
+                }
+                notification.body = (fits > 0).then(|| format!("{}{}", &body[..fits], TRIM_MARK));
+            },
+        }
+        tracing::debug!("Payload trimmed: {:?}", field);
+        serialized = serialize_payload(notification.clone(), format, tracking);
+    }
+
+    tracing::debug!("Payload built: {} bytes", serialized.len());
+    Ok(serialized)
+}
+
+///Fields the target browser will ignore, according to the capabilities of the subscription
+pub fn capability_warnings(notification: &Notification, capabilities: Option<&Capabilities>, path: &str) -> Vec<FieldError> {
+    let mut warnings = Vec::new();
+    let Some(capabilities) = capabilities else {
+        return warnings;
+    };
+
+    if let Some(max) = capabilities.max_actions {
+        //La action default no lleva boton
+        let buttons: Vec<_> = notification.actions.iter().flatten()
+            .enumerate()
+            .filter(|(i, a)| a.id(*i) != "default")
+            .collect();
+        for (i, _) in buttons.iter().skip(max) {
+            warnings.push(FieldError::new(format!("{path}.actions[{i}]"), format!("the browser shows at most {max} actions, this one will be dropped")));
+        }
+    }
+
+    if notification.image.is_some() && capabilities.supports_images == Some(false) {
+        warnings.push(FieldError::new(format!("{path}.image"), "the browser doesn't show images"));
+    }
+
+    warnings
+}
+
+///Builds the payload once for each format used by the subscriptions
+pub fn build_payloads(conf: &PayloadConf, payload: &PayLoad, subscriptions: &[Subscription], tracking: Option<&Tracking>) -> Result<HashMap<PayloadFormat, String>, PayloadTooLarge> {
+    let mut built = HashMap::new();
+    for sub in subscriptions {
+        if let Entry::Vacant(entry) = built.entry(sub.format()) {
+            //aes128gcm tiene mas overhead, si entra con esa entra con cualquiera
+            entry.insert(build_payload(conf, payload.clone(), Encoding::Aes128gcm, sub.format(), tracking)?);
+        }
+    }
+    Ok(built)
+}
+
+///Se agrega al final del body recortado
+const TRIM_MARK: &str = "…";
+
+#[derive(Deserialize, Serialize, ToSchema, Debug)]
+pub struct NotifyResult {
+    pub message   : String,
+    ///Id of the message in GET /stats/messages/{id}. Only when the notification carries tracking data
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    ///Fields the target browser will ignore
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings  : Vec<FieldError>,
+}
+
+///Body of a sent push. Only the message, as a JSON string, when there is no message id or warning to report
+#[derive(Deserialize, Serialize, ToSchema, Debug)]
+#[serde(untagged)]
+pub enum NotifySent {
+    Message(String),
+    Result(NotifyResult),
+}
+
+impl NotifySent {
+    pub fn new(message_id: Option<String>, warnings: Vec<FieldError>) -> Self {
+        let message = "Push sent successfully".to_owned();
+        //Sin nada mas que informar se mantiene el string de antes, para no romper a los que ya lo leen
+        if message_id.is_none() && warnings.is_empty() {
+            NotifySent::Message(message)
+        } else {
+            NotifySent::Result(NotifyResult { message, message_id, warnings })
+        }
+    }
+}
+
+///Error body with the X-Request-Id of the request, to find it in the logs
+#[derive(Deserialize, Serialize, ToSchema, Debug)]
+pub struct NotifyError<T> {
+    pub request_id: String,
+    pub error     : T,
+}
+
+impl<T> NotifyError<T> {
+    pub fn new(error: T) -> Self {
+        Self { request_id: request_id::current(), error }
+    }
+}
+
 #[derive(utoipa::IntoResponses,Deserialize,Serialize, ToSchema)]
 pub enum NotifyResponses {
     /// Success response
     #[response(status = 200)]
-    Ok(String),
+    Ok(NotifySent),
+
+    /// The notification was scheduled. Returns the message id
+    #[response(status = 202)]
+    Scheduled(String),
 
     #[response(status = 404)]
     NotFound,
 
+    /// Every invalid field of the request
     #[response(status = 400)]
-    BadRequest(String),
+    BadRequest(NotifyError<Vec<FieldError>>),
     #[response(status = 500)]
-    InternalServerError(String),
+    InternalServerError(NotifyError<String>),
+
+    /// The encrypted payload exceeds the push service limit even after trimming
+    #[response(status = 413)]
+    PayloadTooLarge(NotifyError<PayloadTooLarge>),
+
+    /// Rate limit exceeded. Seconds to wait are sent in the Retry-After header
+    #[response(status = 429)]
+    TooManyRequests(u64),
 }
 
 impl IntoResponse for NotifyResponses {
     fn into_response(self) -> axum::response::Response {
         match self {
-            NotifyResponses::Ok(msg) => (StatusCode::OK, Json(msg)).into_response(),
+            NotifyResponses::Ok(result) => (StatusCode::OK, Json(result)).into_response(),
+            NotifyResponses::Scheduled(id) => (StatusCode::ACCEPTED, Json(id)).into_response(),
             NotifyResponses::NotFound => (StatusCode::NOT_FOUND, Json("Not Found")).into_response(),
             NotifyResponses::BadRequest(msg) => (StatusCode::BAD_REQUEST, Json(msg)).into_response(),
             NotifyResponses::InternalServerError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, Json(msg)).into_response(),
+            NotifyResponses::PayloadTooLarge(size) => (StatusCode::PAYLOAD_TOO_LARGE, Json(size)).into_response(),
+            NotifyResponses::TooManyRequests(secs) => too_many_requests(Duration::from_secs(secs)),
+        }
+    }
+}
+
+impl From<PushError> for NotifyResponses {
+    fn from(value: PushError) -> Self {
+        match value {
+            PushError::Vapid(msg) => NotifyResponses::InternalServerError(NotifyError::new(msg)),
+            PushError::Send(_) => NotifyResponses::InternalServerError(NotifyError::new("Failed to send push".into())),
+            PushError::EndpointNotAllowed(msg) => NotifyResponses::BadRequest(NotifyError::new(vec![FieldError::new("subscription.endpoint", msg)])),
+            PushError::RateLimited(wait) => {
+                info!("Push rate limited for {:?}", wait);
+                NotifyResponses::TooManyRequests(wait.as_secs_f64().ceil() as u64)
+            },
         }
     }
 }
 
-#[utoipa::path(post, path = "/notify", responses(NotifyResponses))]
+#[utoipa::path(post, path = "/notify", params(("Idempotency-Key" = Option<String>, Header, description = "Repeats with the same key return the first response without sending again")), responses(NotifyResponses))]
+#[tracing::instrument(skip_all)]
 pub async fn notify(
-    State(conf): State<Arc<KeysJson>>,
-    Json(mut req): Json<NotificationRequest>,
+    State(state): State<Arc<AppState>>,
+    Extension(caller): Extension<Caller>,
+    Json(req): Json<NotificationRequest>,
 ) -> NotifyResponses {
-    // Build subscription info
-    info!("req: {:?}",&req);
-    let sub = SubscriptionInfo::new(
-        req.subscription.endpoint,
-        req.subscription.keys.p256dh,
-        req.subscription.keys.auth,
+    info!(
+        endpoint = push::origin(&req.subscription.endpoint),
+        encoding = ?req.subscription.content_encoding,
+        mode     = ?req.mode,
+        template = req.template.as_ref().map(|t| t.id.as_str()),
+        send_at  = req.send_at,
+        "Notify request"
     );
-    
+    if state.logging.notification_content && let Some(payload) = &req.payload {
+        info!(notification = ?payload.notification, "Notify request content");
+    }
 
-    //Armar Payload
-    if req.payload.notification.timestamp.is_none() {
-        req.payload.notification.timestamp = Some(Utc::now().timestamp_millis().try_into().unwrap())
+    let mut errors = Vec::new();
+    if let Err(msg) = allowlist::check(&state.endpoints, &req.subscription.endpoint).await {
+        errors.push(FieldError::new("subscription.endpoint", msg));
     }
+    validation::subscription(&req.subscription, "subscription", &mut errors);
 
-    let notif_push: NotifPush = req.payload.notification.into();
-    let payload = json!({"notification": notif_push});
-    let payload = serde_json::to_string(&payload).unwrap();//.into_bytes();
-    tracing::debug!("Payload: {}", payload);
-    let payload = payload.as_bytes();
-
-    // Build VAPID signature (set your mailto subject)
-    let private_key = &conf.private_key;
-    let sig = match VapidSignatureBuilder::from_base64(&private_key, &sub) {
-        Ok(b) => match b.build() {
-            Ok(s) => s,
-            Err(e) => {
-                tracing::error!("Failed to build VAPID signature: {}", e);
-                return NotifyResponses::InternalServerError("VAPID signature error".into());
-            }
+    let template_id = req.template.as_ref().map(|t| t.id.clone());
+    let mut warnings = Vec::new();
+    let payload = match (req.mode, req.payload, req.template) {
+        (NotifyMode::Empty, None, None) => Ok(None),
+        (NotifyMode::Empty, _, _) => Err(FieldError::new("mode", "empty pushes can't have payload or template")),
+        (NotifyMode::Notification, Some(payload), None) => {
+            validation::notification(&payload.notification, "payload.notification", &mut errors);
+            warnings = capability_warnings(&payload.notification, req.subscription.capabilities.as_ref(), "payload.notification");
+            Ok(Some(payload))
         },
+        (NotifyMode::Notification, None, Some(template)) => match state.templates.render(&caller.0, &template) {
+            Ok(notification) => {
+                validation::notification(&notification, "template", &mut errors);
+                warnings = capability_warnings(&notification, req.subscription.capabilities.as_ref(), "template");
+                Ok(Some(PayLoad { notification }))
+            },
+            Err(msg) => Err(FieldError::new("template", msg)),
+        },
+        (NotifyMode::Notification, _, _) => Err(FieldError::new("payload", "exactly one of payload or template must be set")),
+    };
+
+    let payload = match payload {
+        Ok(payload) => payload,
         Err(e) => {
-            tracing::error!("Failed to parse VAPID PEM: {}", e);
-            return NotifyResponses::InternalServerError("VAPID PEM parse error".into());
+            errors.push(e);
+            None
+        },
+    };
+
+    if !errors.is_empty() {
+        info!("Invalid request: {:?}", errors);
+        return NotifyResponses::BadRequest(NotifyError::new(errors));
+    }
+
+    let context = SendContext::new(&caller.0, template_id.as_deref(), payload.as_ref().map(|p| p.notification.title.as_str()));
+
+    //Armar Payload. Los programados se arman de nuevo al enviarlos, con el id del mensaje programado
+    let format = req.format.unwrap_or(req.subscription.format());
+    let tracking = payload.as_ref().and_then(|p| state.events.track(&uuid::Uuid::new_v4().to_string(), template_id.as_deref(), None, &p.notification));
+    let built = match payload.clone().map(|p| build_payload(&state.payload, p, req.subscription.content_encoding, format, tracking.as_ref())).transpose() {
+        Ok(p) => p,
+        Err(size) => {
+            info!("Payload too large: {} bytes, max {}", size.size, size.max_size);
+            return NotifyResponses::PayloadTooLarge(NotifyError::new(size));
         }
     };
 
-    // Create message builder and optional payload
-    let mut builder = WebPushMessageBuilder::new(&sub);
-    builder.set_payload(ContentEncoding::Aes128Gcm, &payload);
-    builder.set_vapid_signature(sig);
+    if state.logging.notification_content && let Some(built) = &built {
+        tracing::debug!("Payload: {}", built);
+    }
 
-    // Create client and send
-    let client = HyperWebPushClient::new();
+    if let Some(send_at) = req.send_at && send_at > now_millis() {
+        let id = state.scheduler.schedule(send_at, req.subscription, payload, format, context);
+        info!("Push scheduled with id {}", id);
+        return NotifyResponses::Scheduled(id);
+    }
 
-    match client.send(builder.build().unwrap()).await {
+    match push::send(&state, &req.subscription, built.as_ref().map(String::as_bytes), &context).await {
         Ok(_) => {
             info!("Push sent");
-            NotifyResponses::Ok("Push sent successfully".into())
-        }
-        Err(e) => {
-            tracing::error!("Failed to send push: {}", e);
-            NotifyResponses::InternalServerError("Failed to send push".into())
+            if let Some(tracking) = &tracking {
+                state.events.sent(tracking, &context.caller, 1);
+            }
+            NotifyResponses::Ok(NotifySent::new(tracking.map(|t| t.id), warnings))
         }
+        Err(e) => e.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///Los campos que lee assets/sw.js
+    #[test]
+    fn angular_payload_has_the_fields_read_by_sw_js() {
+        let notification: Notification = serde_json::from_value(json!({
+            "title": "Order shipped",
+            "requireInteraction": true,
+            "data": {"order": 7},
+            "actions": [
+                {"action": "track", "title": "Track", "operation": "openWindow", "url": "/orders/7"},
+                {"title": "default", "operation": "focusLastFocusedOrOpen", "url": "/"},
+            ],
+        })).unwrap();
+        let tracking = Tracking {
+            id      : "message-1".to_owned(),
+            template: None,
+            topic   : Some("orders".to_owned()),
+            actions : notification.action_ids(),
+            token   : "token".to_owned(),
+        };
+
+        let payload = build_payload(&PayloadConf::default(), PayLoad { notification }, Encoding::Aes128gcm, PayloadFormat::Angular, Some(&tracking)).unwrap();
+        let payload: Value = serde_json::from_str(&payload).unwrap();
+        let notification = &payload["notification"];
+
+        assert_eq!(notification["title"], "Order shipped");
+        assert_eq!(notification["requireInteraction"], true);
+        assert!(notification.get("require_interaction").is_none());
+        assert_eq!(notification["actions"], json!([{"action": "track", "title": "Track"}]));
+
+        let data = &notification["data"];
+        assert_eq!(data["order"], 7);
+        assert_eq!(data["onActionClick"]["track"], json!({"operation": "openWindow", "url": "/orders/7"}));
+        assert_eq!(data["onActionClick"]["default"], json!({"operation": "focusLastFocusedOrOpen", "url": "/"}));
+        assert_eq!(data["tracking"], json!({"id": "message-1", "topic": "orders", "actions": ["track"], "token": "token"}));
     }
-}
\ No newline at end of file
+
+    fn notification(body: &str, data: Option<Value>) -> PayLoad {
+        PayLoad { notification: Notification { title: "Title".to_owned(), body: Some(body.to_owned()), data, timestamp: Some(1_700_000_000_000), ..Default::default() } }
+    }
+
+    #[test]
+    fn trims_a_multibyte_body_to_fit() {
+        let conf = PayloadConf { max_size: 600, trim: vec![TrimField::Body], ..Default::default() };
+        let body = "ñ\"".repeat(500);
+
+        let payload = build_payload(&conf, notification(&body, None), Encoding::Aes128gcm, PayloadFormat::Angular, None).unwrap();
+        assert!(push::encrypted_size(payload.len(), Encoding::Aes128gcm) <= 600);
+
+        let payload: Value = serde_json::from_str(&payload).unwrap();
+        let trimmed = payload["notification"]["body"].as_str().unwrap();
+        let kept = trimmed.strip_suffix(TRIM_MARK).unwrap();
+        assert!(body.starts_with(kept));
+        //El mas largo que entra: un caracter mas ya no entra
+        let longer = &body[..kept.len() + body[kept.len()..].chars().next().unwrap().len_utf8()];
+        let longer = build_payload(&PayloadConf { trim: Vec::new(), ..conf }, notification(&format!("{longer}{TRIM_MARK}"), None), Encoding::Aes128gcm, PayloadFormat::Angular, None);
+        assert!(longer.is_err());
+    }
+
+    #[test]
+    fn trims_fields_in_the_configured_order() {
+        let conf = PayloadConf { max_size: 1000, trim: vec![TrimField::Data, TrimField::Body], ..Default::default() };
+        let data = json!({"blob": "x".repeat(2000)});
+
+        let payload = build_payload(&conf, notification("Short body", Some(data)), Encoding::Aes128gcm, PayloadFormat::Angular, None).unwrap();
+        let payload: Value = serde_json::from_str(&payload).unwrap();
+        assert!(payload["notification"].get("data").is_none());
+        assert_eq!(payload["notification"]["body"], "Short body");
+    }
+
+    #[test]
+    fn reports_the_size_of_a_payload_that_doesnt_fit() {
+        let conf = PayloadConf { max_size: 4096, trim: Vec::new(), ..Default::default() };
+        let payload = notification(&"a".repeat(5000), None);
+        let serialized = serialize_payload(payload.notification.clone(), PayloadFormat::Angular, None);
+
+        let too_large = build_payload(&conf, payload, Encoding::Aesgcm, PayloadFormat::Angular, None).unwrap_err();
+        assert_eq!(too_large.size, push::encrypted_size(serialized.len(), Encoding::Aesgcm));
+        //Limitado por el plaintext maximo que cifra web-push
+        assert_eq!(too_large.max_size, push::max_encrypted_size(4096, Encoding::Aesgcm));
+        assert!(too_large.max_size < 4096);
+    }
+}
@@ -0,0 +1,73 @@
+use std::sync::Arc;
+
+use axum::{Extension, Json, extract::State};
+use base64::{Engine, prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD}};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::info;
+use utoipa::ToSchema;
+
+use crate::{allowlist, audit::SendContext, auth::Caller, push, routes::notify::{NotifyError, NotifyResponses, NotifySent, PayloadTooLarge, Subscription}, state::AppState, validation::{self, FieldError}};
+
+///Data sent as is to the service worker, which reads it with `event.data.json()` or `event.data.arrayBuffer()`
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone)]
+#[serde(rename_all="camelCase")]
+pub enum RawPayload {
+    ///Any json value
+    Json(Value),
+    ///Binary data encoded in base64 (standard or url safe)
+    Base64(String),
+}
+
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone)]
+pub struct RawNotificationRequest {
+    pub subscription: Subscription,
+    pub payload     : RawPayload,
+}
+
+///Encrypts and sends an arbitrary payload, without building a notification
+#[utoipa::path(post, path = "/notify/raw", params(("Idempotency-Key" = Option<String>, Header, description = "Repeats with the same key return the first response without sending again")), responses(NotifyResponses))]
+pub async fn notify_raw(
+    State(state): State<Arc<AppState>>,
+    Extension(caller): Extension<Caller>,
+    Json(req): Json<RawNotificationRequest>,
+) -> NotifyResponses {
+    info!(endpoint = push::origin(&req.subscription.endpoint), encoding = ?req.subscription.content_encoding, "Raw notify request");
+
+    let mut errors = Vec::new();
+    if let Err(msg) = allowlist::check(&state.endpoints, &req.subscription.endpoint).await {
+        errors.push(FieldError::new("subscription.endpoint", msg));
+    }
+    validation::subscription(&req.subscription, "subscription", &mut errors);
+
+    let payload = match req.payload {
+        RawPayload::Json(value) => serde_json::to_vec(&value).unwrap(),
+        RawPayload::Base64(encoded) => match BASE64_STANDARD.decode(&encoded).or_else(|_| BASE64_URL_SAFE_NO_PAD.decode(encoded.trim_end_matches('='))) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                errors.push(FieldError::new("payload.base64", format!("is not valid base64: {e}")));
+                Vec::new()
+            },
+        },
+    };
+
+    if !errors.is_empty() {
+        info!("Invalid request: {:?}", errors);
+        return NotifyResponses::BadRequest(NotifyError::new(errors));
+    }
+
+    let size = push::encrypted_size(payload.len(), req.subscription.content_encoding);
+    let max_size = push::max_encrypted_size(state.payload.max_size, req.subscription.content_encoding);
+    if size > max_size {
+        info!("Payload too large: {} bytes, max {}", size, max_size);
+        return NotifyResponses::PayloadTooLarge(NotifyError::new(PayloadTooLarge { size, max_size }));
+    }
+
+    match push::send(&state, &req.subscription, Some(&payload), &SendContext::new(&caller.0, None, None)).await {
+        Ok(_) => {
+            info!("Raw push sent");
+            NotifyResponses::Ok(NotifySent::new(None, Vec::new()))
+        }
+        Err(e) => e.into(),
+    }
+}
@@ -0,0 +1,159 @@
+use std::sync::Arc;
+
+use axum::{Extension, Json, extract::{Path, State}, http::StatusCode, response::IntoResponse};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use utoipa::ToSchema;
+
+use crate::{auth::Caller, recurring::{Campaign, CampaignInvalid, CampaignRequest}, routes::notify::PayloadTooLarge, state::AppState, validation::FieldError};
+
+#[derive(utoipa::IntoResponses,Deserialize,Serialize, ToSchema)]
+pub enum CampaignResponses {
+    /// Success response
+    #[response(status = 200)]
+    Ok(Campaign),
+
+    /// The campaign was created
+    #[response(status = 201)]
+    Created(Campaign),
+
+    #[response(status = 404)]
+    NotFound,
+
+    /// Every invalid field of the campaign
+    #[response(status = 400)]
+    BadRequest(Vec<FieldError>),
+
+    /// The encrypted payload exceeds the push service limit even after trimming
+    #[response(status = 413)]
+    PayloadTooLarge(PayloadTooLarge),
+}
+
+impl From<CampaignInvalid> for CampaignResponses {
+    fn from(value: CampaignInvalid) -> Self {
+        match value {
+            CampaignInvalid::Fields(errors) => CampaignResponses::BadRequest(errors),
+            CampaignInvalid::PayloadTooLarge(size) => {
+                info!("Payload too large: {} bytes, max {}", size.size, size.max_size);
+                CampaignResponses::PayloadTooLarge(size)
+            },
+        }
+    }
+}
+
+impl IntoResponse for CampaignResponses {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            CampaignResponses::Ok(c) => (StatusCode::OK, Json(c)).into_response(),
+            CampaignResponses::Created(c) => (StatusCode::CREATED, Json(c)).into_response(),
+            CampaignResponses::NotFound => (StatusCode::NOT_FOUND, Json("Not Found")).into_response(),
+            CampaignResponses::BadRequest(errors) => (StatusCode::BAD_REQUEST, Json(errors)).into_response(),
+            CampaignResponses::PayloadTooLarge(size) => (StatusCode::PAYLOAD_TOO_LARGE, Json(size)).into_response(),
+        }
+    }
+}
+
+#[derive(utoipa::IntoResponses,Deserialize,Serialize, ToSchema)]
+pub enum ListCampaignsResponses {
+    /// Success response
+    #[response(status = 200)]
+    Ok(Vec<Campaign>),
+}
+
+impl IntoResponse for ListCampaignsResponses {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            ListCampaignsResponses::Ok(c) => (StatusCode::OK, Json(c)).into_response(),
+        }
+    }
+}
+
+#[derive(utoipa::IntoResponses,Deserialize,Serialize, ToSchema)]
+pub enum DeleteCampaignResponses {
+    /// The campaign was removed
+    #[response(status = 200)]
+    Ok(String),
+
+    #[response(status = 404)]
+    NotFound,
+}
+
+impl IntoResponse for DeleteCampaignResponses {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            DeleteCampaignResponses::Ok(msg) => (StatusCode::OK, Json(msg)).into_response(),
+            DeleteCampaignResponses::NotFound => (StatusCode::NOT_FOUND, Json("Not Found")).into_response(),
+        }
+    }
+}
+
+///Creates a recurring notification sent to every subscription each time the cron expression fires
+#[utoipa::path(post, path = "/recurring", responses(CampaignResponses))]
+pub async fn create_campaign(
+    State(state): State<Arc<AppState>>,
+    Extension(caller): Extension<Caller>,
+    Json(req): Json<CampaignRequest>,
+) -> CampaignResponses {
+    if let Err(invalid) = req.validate(&state).await {
+        return invalid.into();
+    }
+
+    let campaign = state.recurring.create(req, &caller.0);
+    info!("Recurring push {} created", campaign.id);
+    CampaignResponses::Created(campaign)
+}
+
+///Lists the campaigns created by the api key
+#[utoipa::path(get, path = "/recurring", responses(ListCampaignsResponses))]
+pub async fn list_campaigns(
+    State(state): State<Arc<AppState>>,
+    Extension(caller): Extension<Caller>,
+) -> ListCampaignsResponses {
+    ListCampaignsResponses::Ok(state.recurring.list(&caller.0))
+}
+
+#[utoipa::path(get, path = "/recurring/{id}", params(("id" = String, Path)), responses(CampaignResponses))]
+pub async fn get_campaign(
+    State(state): State<Arc<AppState>>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> CampaignResponses {
+    match state.recurring.get(&id, &caller.0) {
+        Some(c) => CampaignResponses::Ok(c),
+        None => CampaignResponses::NotFound,
+    }
+}
+
+#[utoipa::path(put, path = "/recurring/{id}", params(("id" = String, Path)), responses(CampaignResponses))]
+pub async fn update_campaign(
+    State(state): State<Arc<AppState>>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+    Json(req): Json<CampaignRequest>,
+) -> CampaignResponses {
+    if let Err(invalid) = req.validate(&state).await {
+        return invalid.into();
+    }
+
+    match state.recurring.update(&id, req, &caller.0) {
+        Some(c) => {
+            info!("Recurring push {} updated", c.id);
+            CampaignResponses::Ok(c)
+        },
+        None => CampaignResponses::NotFound,
+    }
+}
+
+#[utoipa::path(delete, path = "/recurring/{id}", params(("id" = String, Path)), responses(DeleteCampaignResponses))]
+pub async fn delete_campaign(
+    State(state): State<Arc<AppState>>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> DeleteCampaignResponses {
+    if state.recurring.delete(&id, &caller.0) {
+        info!("Recurring push {} deleted", id);
+        DeleteCampaignResponses::Ok("Campaign deleted".into())
+    } else {
+        DeleteCampaignResponses::NotFound
+    }
+}
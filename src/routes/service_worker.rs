@@ -0,0 +1,106 @@
+use std::sync::Arc;
+
+use axum::{extract::State, http::{HeaderMap, HeaderValue, StatusCode, header}, response::IntoResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+use crate::state::AppState;
+
+///Version de los scripts. Cambia con cada version del servidor, junto con el formato del payload
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+const SW_JS: &str = include_str!("../../assets/sw.js");
+const CLIENT_JS: &str = include_str!("../../assets/webpush-client.js");
+
+#[derive(utoipa::IntoResponses,Deserialize,Serialize, ToSchema)]
+pub enum ScriptResponses {
+    /// The script, with the configuration of the server
+    #[response(status = 200, content_type = "application/javascript")]
+    Ok(String),
+
+    /// The script didn't change since the ETag sent in If-None-Match
+    #[response(status = 304)]
+    NotModified(String),
+}
+
+impl IntoResponse for ScriptResponses {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            ScriptResponses::Ok(script) => {
+                let mut response = (StatusCode::OK, script.clone()).into_response();
+                let headers = response.headers_mut();
+                headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/javascript; charset=utf-8"));
+                headers.insert(header::ETAG, HeaderValue::from_str(&etag(&script)).unwrap());
+                //Los navegadores siempre revalidan, asi toman la version nueva del servidor
+                headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+                headers.insert("service-worker-allowed", HeaderValue::from_static("/"));
+                response
+            },
+            ScriptResponses::NotModified(etag) => {
+                let mut response = StatusCode::NOT_MODIFIED.into_response();
+                response.headers_mut().insert(header::ETAG, HeaderValue::from_str(&etag).unwrap());
+                response
+            },
+        }
+    }
+}
+
+///Service worker for the `angular` payload format: shows the notification, runs the onActionClick operations and reports events to POST /events. Doesn't require api_key
+#[utoipa::path(get, path = "/sw.js", responses(ScriptResponses))]
+pub async fn sw_js(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ScriptResponses {
+    let config = json!({
+        "version"    : VERSION,
+        "serverUrl"  : server_url(&state, &headers),
+        "trackEvents": state.service_worker.track_events,
+    });
+    script(config, SW_JS, &headers)
+}
+
+///Client that subscribes the browser with the server public key and sends the subscription to `register_url`. Doesn't require api_key
+#[utoipa::path(get, path = "/webpush-client.js", responses(ScriptResponses))]
+pub async fn client_js(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> ScriptResponses {
+    let config = json!({
+        "version"    : VERSION,
+        "serverUrl"  : server_url(&state, &headers),
+        "registerUrl": state.service_worker.register_url,
+    });
+    script(config, CLIENT_JS, &headers)
+}
+
+///Antepone la configuracion al script
+fn script(config: serde_json::Value, source: &str, headers: &HeaderMap) -> ScriptResponses {
+    let script = format!("const WEBPUSH_CONFIG = {};\n{}", config, source);
+    let etag = etag(&script);
+    let cached = headers.get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').any(|t| t.trim() == etag));
+    if cached {
+        ScriptResponses::NotModified(etag)
+    } else {
+        ScriptResponses::Ok(script)
+    }
+}
+
+fn etag(script: &str) -> String {
+    let hash: String = Sha256::digest(script.as_bytes())[..8].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("\"{}-{}\"", VERSION, hash)
+}
+
+///public_url de la configuracion, o el Host con el que llego la request
+fn server_url(state: &AppState, headers: &HeaderMap) -> String {
+    if let Some(url) = &state.service_worker.public_url {
+        return url.trim_end_matches('/').to_owned();
+    }
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    //Solo http o https, un cache intermedio podria guardar el script con otro valor
+    let proto = if header("x-forwarded-proto") == Some("https") { "https" } else { "http" };
+    format!("{}://{}", proto, header("host").unwrap_or("localhost"))
+}
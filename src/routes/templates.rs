@@ -0,0 +1,120 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::{Extension, Json, extract::{Path, State}, http::StatusCode, response::IntoResponse};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use utoipa::ToSchema;
+
+use crate::{auth::Caller, state::AppState, templates::Template, validation::{self, FieldError}};
+
+#[derive(utoipa::IntoResponses,Deserialize,Serialize, ToSchema)]
+pub enum ListTemplatesResponses {
+    /// Templates by id
+    #[response(status = 200)]
+    Ok(BTreeMap<String, Template>),
+}
+
+impl IntoResponse for ListTemplatesResponses {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            ListTemplatesResponses::Ok(t) => (StatusCode::OK, Json(t)).into_response(),
+        }
+    }
+}
+
+#[derive(utoipa::IntoResponses,Deserialize,Serialize, ToSchema)]
+pub enum TemplateResponses {
+    /// Success response
+    #[response(status = 200)]
+    Ok(Template),
+
+    #[response(status = 404)]
+    NotFound,
+
+    /// Every invalid field of the template
+    #[response(status = 400)]
+    BadRequest(Vec<FieldError>),
+}
+
+impl IntoResponse for TemplateResponses {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            TemplateResponses::Ok(t) => (StatusCode::OK, Json(t)).into_response(),
+            TemplateResponses::NotFound => (StatusCode::NOT_FOUND, Json("Not Found")).into_response(),
+            TemplateResponses::BadRequest(errors) => (StatusCode::BAD_REQUEST, Json(errors)).into_response(),
+        }
+    }
+}
+
+#[derive(utoipa::IntoResponses,Deserialize,Serialize, ToSchema)]
+pub enum DeleteTemplateResponses {
+    /// The template was removed
+    #[response(status = 200)]
+    Ok(String),
+
+    #[response(status = 404)]
+    NotFound,
+}
+
+impl IntoResponse for DeleteTemplateResponses {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            DeleteTemplateResponses::Ok(msg) => (StatusCode::OK, Json(msg)).into_response(),
+            DeleteTemplateResponses::NotFound => (StatusCode::NOT_FOUND, Json("Not Found")).into_response(),
+        }
+    }
+}
+
+///Lists the templates of the api key
+#[utoipa::path(get, path = "/templates", responses(ListTemplatesResponses))]
+pub async fn list_templates(
+    State(state): State<Arc<AppState>>,
+    Extension(caller): Extension<Caller>,
+) -> ListTemplatesResponses {
+    ListTemplatesResponses::Ok(state.templates.list(&caller.0))
+}
+
+#[utoipa::path(get, path = "/templates/{id}", params(("id" = String, Path)), responses(TemplateResponses))]
+pub async fn get_template(
+    State(state): State<Arc<AppState>>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> TemplateResponses {
+    match state.templates.get(&caller.0, &id) {
+        Some(t) => TemplateResponses::Ok(t),
+        None => TemplateResponses::NotFound,
+    }
+}
+
+///Creates or replaces a template
+#[utoipa::path(put, path = "/templates/{id}", params(("id" = String, Path)), responses(TemplateResponses))]
+pub async fn put_template(
+    State(state): State<Arc<AppState>>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+    Json(template): Json<Template>,
+) -> TemplateResponses {
+    let mut errors = Vec::new();
+    validation::template(&template, &mut errors);
+    if !errors.is_empty() {
+        return TemplateResponses::BadRequest(errors);
+    }
+
+    state.templates.upsert(&caller.0, &id, template.clone());
+    info!("Template {} saved", id);
+    TemplateResponses::Ok(template)
+}
+
+#[utoipa::path(delete, path = "/templates/{id}", params(("id" = String, Path)), responses(DeleteTemplateResponses))]
+pub async fn delete_template(
+    State(state): State<Arc<AppState>>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> DeleteTemplateResponses {
+    if state.templates.delete(&caller.0, &id) {
+        info!("Template {} deleted", id);
+        DeleteTemplateResponses::Ok("Template deleted".into())
+    } else {
+        DeleteTemplateResponses::NotFound
+    }
+}
@@ -0,0 +1,195 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{Extension, Json, extract::{Path, Query, State}, http::StatusCode, response::IntoResponse};
+use serde::{Deserialize, Serialize};
+use tracing::{Instrument, Span, info};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{allowlist, audit::SendContext, auth::Caller, events::Tracking, formats::PayloadFormat, push, routes::notify::{Notification, PayLoad, PayloadTooLarge, Subscription, build_payloads, capability_warnings}, state::AppState, topics::{PublishResult, TopicSummary}, validation::{self, FieldError}};
+
+#[derive(utoipa::IntoResponses,Deserialize,Serialize, ToSchema)]
+pub enum ListTopicsResponses {
+    /// Success response
+    #[response(status = 200)]
+    Ok(Vec<TopicSummary>),
+}
+
+impl IntoResponse for ListTopicsResponses {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            ListTopicsResponses::Ok(t) => (StatusCode::OK, Json(t)).into_response(),
+        }
+    }
+}
+
+#[derive(utoipa::IntoResponses,Deserialize,Serialize, ToSchema)]
+pub enum TopicMemberResponses {
+    /// Success response
+    #[response(status = 200)]
+    Ok(String),
+
+    #[response(status = 404)]
+    NotFound,
+
+    /// Every invalid field of the subscription
+    #[response(status = 400)]
+    BadRequest(Vec<FieldError>),
+}
+
+impl IntoResponse for TopicMemberResponses {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            TopicMemberResponses::Ok(msg) => (StatusCode::OK, Json(msg)).into_response(),
+            TopicMemberResponses::NotFound => (StatusCode::NOT_FOUND, Json("Not Found")).into_response(),
+            TopicMemberResponses::BadRequest(errors) => (StatusCode::BAD_REQUEST, Json(errors)).into_response(),
+        }
+    }
+}
+
+#[derive(utoipa::IntoResponses,Deserialize,Serialize, ToSchema)]
+pub enum PublishResponses {
+    /// Delivery statistics of this publication
+    #[response(status = 200)]
+    Ok(PublishResult),
+
+    /// The topic has no members
+    #[response(status = 404)]
+    NotFound,
+
+    /// The encrypted payload exceeds the push service limit even after trimming
+    #[response(status = 413)]
+    PayloadTooLarge(PayloadTooLarge),
+
+    /// Every invalid field of the payload
+    #[response(status = 400)]
+    BadRequest(Vec<FieldError>),
+}
+
+impl IntoResponse for PublishResponses {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            PublishResponses::Ok(r) => (StatusCode::OK, Json(r)).into_response(),
+            PublishResponses::NotFound => (StatusCode::NOT_FOUND, Json("Not Found")).into_response(),
+            PublishResponses::PayloadTooLarge(size) => (StatusCode::PAYLOAD_TOO_LARGE, Json(size)).into_response(),
+            PublishResponses::BadRequest(errors) => (StatusCode::BAD_REQUEST, Json(errors)).into_response(),
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct DetachParams {
+    ///Endpoint of the subscription to remove
+    endpoint: String,
+}
+
+///Lists the topics of the api key with their member count and accumulated delivery statistics
+#[utoipa::path(get, path = "/topics", responses(ListTopicsResponses))]
+pub async fn list_topics(
+    State(state): State<Arc<AppState>>,
+    Extension(caller): Extension<Caller>,
+) -> ListTopicsResponses {
+    ListTopicsResponses::Ok(state.topics.list(&caller.0))
+}
+
+///Adds a subscription to the topic. The topic is created if it doesn't exist
+#[utoipa::path(post, path = "/topics/{name}/subscriptions", params(("name" = String, Path)), responses(TopicMemberResponses))]
+pub async fn attach_subscription(
+    State(state): State<Arc<AppState>>,
+    Extension(caller): Extension<Caller>,
+    Path(name): Path<String>,
+    Json(sub): Json<Subscription>,
+) -> TopicMemberResponses {
+    let mut errors = Vec::new();
+    if let Err(msg) = allowlist::check(&state.endpoints, &sub.endpoint).await {
+        errors.push(FieldError::new("endpoint", msg));
+    }
+    validation::subscription(&sub, "", &mut errors);
+    if !errors.is_empty() {
+        return TopicMemberResponses::BadRequest(errors);
+    }
+
+    state.topics.attach(&caller.0, &name, sub);
+    info!("Subscription attached to topic {}", name);
+    TopicMemberResponses::Ok("Subscription attached".into())
+}
+
+///Removes a subscription from the topic. Empty topics are deleted
+#[utoipa::path(delete, path = "/topics/{name}/subscriptions", params(("name" = String, Path), DetachParams), responses(TopicMemberResponses))]
+pub async fn detach_subscription(
+    State(state): State<Arc<AppState>>,
+    Extension(caller): Extension<Caller>,
+    Path(name): Path<String>,
+    Query(params): Query<DetachParams>,
+) -> TopicMemberResponses {
+    if state.topics.detach(&caller.0, &name, &params.endpoint) {
+        info!("Subscription detached from topic {}", name);
+        TopicMemberResponses::Ok("Subscription detached".into())
+    } else {
+        TopicMemberResponses::NotFound
+    }
+}
+
+///Sends the payload to every member of the topic. Members reported as gone by their push service are removed
+#[utoipa::path(post, path = "/topics/{name}/publish", params(("name" = String, Path)), responses(PublishResponses))]
+pub async fn publish(
+    State(state): State<Arc<AppState>>,
+    Extension(caller): Extension<Caller>,
+    Path(name): Path<String>,
+    Json(payload): Json<PayLoad>,
+) -> PublishResponses {
+    let mut errors = Vec::new();
+    validation::notification(&payload.notification, "notification", &mut errors);
+    if !errors.is_empty() {
+        return PublishResponses::BadRequest(errors);
+    }
+
+    let Some(members) = state.topics.members(&caller.0, &name) else {
+        return PublishResponses::NotFound;
+    };
+
+    let tracking = state.events.track(&uuid::Uuid::new_v4().to_string(), None, Some(&name), &payload.notification);
+    let payloads = match build_payloads(&state.payload, &payload, &members, tracking.as_ref()) {
+        Ok(p) => p,
+        Err(size) => {
+            info!("Payload too large: {} bytes, max {}", size.size, size.max_size);
+            return PublishResponses::PayloadTooLarge(size);
+        }
+    };
+    let warnings = member_warnings(&payload.notification, &members);
+    let context = SendContext::new(&caller.0, None, Some(&payload.notification.title));
+    //En otra tarea, asi si el cliente se desconecta el envio termina y se registra igual
+    let publishing = fan_out(state.clone(), name, members, payloads, context, tracking);
+    let mut result = tokio::spawn(publishing.instrument(Span::current())).await.unwrap();
+    result.warnings = warnings;
+    PublishResponses::Ok(result)
+}
+
+///Campos que ignoran los navegadores de los miembros, agrupados con la cantidad de miembros afectados
+fn member_warnings(notification: &Notification, members: &[Subscription]) -> Vec<FieldError> {
+    let mut counts: Vec<(FieldError, usize)> = Vec::new();
+    for warning in members.iter().flat_map(|m| capability_warnings(notification, m.capabilities.as_ref(), "notification")) {
+        match counts.iter_mut().find(|(w, _)| w.path == warning.path && w.message == warning.message) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((warning, 1)),
+        }
+    }
+    counts.into_iter()
+        .map(|(w, count)| FieldError::new(w.path, format!("{} ({} of {} members)", w.message, count, members.len())))
+        .collect()
+}
+
+///Envia a los miembros y registra el resultado en el topico
+async fn fan_out(state: Arc<AppState>, name: String, members: Vec<Subscription>, payloads: HashMap<PayloadFormat, String>, context: SendContext, tracking: Option<Tracking>) -> PublishResult {
+    let delivery = push::send_all(&state, &members, &payloads, &context, tracking.as_ref()).await;
+    let result = PublishResult {
+        sent      : delivery.sent,
+        failed    : delivery.failed,
+        expired   : delivery.expired.len() as u64,
+        message_id: tracking.map(|t| t.id),
+        ..Default::default()
+    };
+
+    state.topics.record(&context.caller, &name, &result, &delivery.expired);
+    info!("Topic {} published: {} sent, {} failed, {} expired", name, result.sent, result.failed, result.expired);
+    result
+}
@@ -0,0 +1,107 @@
+use std::sync::Arc;
+
+use axum::{Extension, Json, extract::{Path, State}, http::StatusCode, response::IntoResponse};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use utoipa::ToSchema;
+
+use crate::{allowlist, auth::Caller, state::AppState, validation::FieldError, webhooks::{CreatedWebhook, Webhook, WebhookRequest}};
+
+#[derive(utoipa::IntoResponses,Deserialize,Serialize, ToSchema)]
+pub enum CreateWebhookResponses {
+    /// The webhook was registered. The secret isn't returned again
+    #[response(status = 201)]
+    Created(CreatedWebhook),
+
+    /// Every invalid field of the webhook
+    #[response(status = 400)]
+    BadRequest(Vec<FieldError>),
+}
+
+impl IntoResponse for CreateWebhookResponses {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            CreateWebhookResponses::Created(w) => (StatusCode::CREATED, Json(w)).into_response(),
+            CreateWebhookResponses::BadRequest(errors) => (StatusCode::BAD_REQUEST, Json(errors)).into_response(),
+        }
+    }
+}
+
+#[derive(utoipa::IntoResponses,Deserialize,Serialize, ToSchema)]
+pub enum ListWebhooksResponses {
+    /// Webhooks of the api key
+    #[response(status = 200)]
+    Ok(Vec<Webhook>),
+}
+
+impl IntoResponse for ListWebhooksResponses {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            ListWebhooksResponses::Ok(w) => (StatusCode::OK, Json(w)).into_response(),
+        }
+    }
+}
+
+#[derive(utoipa::IntoResponses,Deserialize,Serialize, ToSchema)]
+pub enum DeleteWebhookResponses {
+    /// The webhook was removed
+    #[response(status = 200)]
+    Ok(String),
+
+    #[response(status = 404)]
+    NotFound,
+}
+
+impl IntoResponse for DeleteWebhookResponses {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            DeleteWebhookResponses::Ok(msg) => (StatusCode::OK, Json(msg)).into_response(),
+            DeleteWebhookResponses::NotFound => (StatusCode::NOT_FOUND, Json("Not Found")).into_response(),
+        }
+    }
+}
+
+///Registers a url that receives signed events for the pushes of the api key
+#[utoipa::path(post, path = "/webhooks", responses(CreateWebhookResponses))]
+pub async fn create_webhook(
+    State(state): State<Arc<AppState>>,
+    Extension(caller): Extension<Caller>,
+    Json(req): Json<WebhookRequest>,
+) -> CreateWebhookResponses {
+    let mut errors = Vec::new();
+    if let Err(msg) = allowlist::check_webhook(&state.endpoints, &req.url).await {
+        errors.push(FieldError::new("url", msg));
+    }
+    if req.events.is_empty() {
+        errors.push(FieldError::new("events", "can't be empty"));
+    }
+    if !errors.is_empty() {
+        return CreateWebhookResponses::BadRequest(errors);
+    }
+
+    let created = state.webhooks.create(&caller.0, req);
+    info!("Webhook {} registered by {}", created.webhook.id, caller.0);
+    CreateWebhookResponses::Created(created)
+}
+
+#[utoipa::path(get, path = "/webhooks", responses(ListWebhooksResponses))]
+pub async fn list_webhooks(
+    State(state): State<Arc<AppState>>,
+    Extension(caller): Extension<Caller>,
+) -> ListWebhooksResponses {
+    ListWebhooksResponses::Ok(state.webhooks.list(&caller.0))
+}
+
+#[utoipa::path(delete, path = "/webhooks/{id}", params(("id" = String, Path)), responses(DeleteWebhookResponses))]
+pub async fn delete_webhook(
+    State(state): State<Arc<AppState>>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> DeleteWebhookResponses {
+    if state.webhooks.delete(&caller.0, &id) {
+        info!("Webhook {} deleted", id);
+        DeleteWebhookResponses::Ok("Webhook deleted".into())
+    } else {
+        DeleteWebhookResponses::NotFound
+    }
+}
@@ -0,0 +1,177 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tracing::{Instrument, info, info_span, trace};
+
+use crate::{audit::SendContext, formats::PayloadFormat, push, routes::notify::{PayLoad, Subscription, build_payload}, state::AppState, store::JsonStore};
+
+///Tiempo maximo que duerme el worker si no hay mensajes pendientes
+const IDLE_WAIT: Duration = Duration::from_secs(60);
+///Intentos de un mensaje con errores transitorios antes de descartarlo
+const MAX_ATTEMPTS: u32 = 5;
+///Espera antes del primer reintento, se duplica en cada uno
+const RETRY_WAIT: Duration = Duration::from_secs(30);
+///Espera maxima entre reintentos. Un limite con per_second 0 nunca libera y pide Duration::MAX
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ScheduledMessage {
+    pub id          : String,
+    ///Unix time in milliseconds
+    pub send_at     : u64,
+    pub subscription: Subscription,
+    ///None for empty pushes
+    pub payload     : Option<PayLoad>,
+    #[serde(default)]
+    pub format      : PayloadFormat,
+    ///Who scheduled it, with the X-Request-Id of the request
+    #[serde(flatten)]
+    pub context     : SendContext,
+    ///Attempts that failed with a transient error
+    #[serde(default)]
+    pub attempts    : u32,
+}
+
+///Mensajes programados, persistidos en scheduled.json junto al ejecutable
+pub struct Scheduler {
+    messages: JsonStore<Vec<ScheduledMessage>>,
+    wake    : Notify,
+}
+
+impl Scheduler {
+    pub fn load() -> Self {
+        Self { messages: JsonStore::load("scheduled.json"), wake: Notify::new() }
+    }
+
+    ///Guarda los cambios, lo llama el worker de store
+    pub fn flush(&self) {
+        self.messages.flush();
+    }
+
+    ///Guarda el mensaje y devuelve su id
+    pub fn schedule(&self, send_at: u64, subscription: Subscription, payload: Option<PayLoad>, format: PayloadFormat, context: SendContext) -> String {
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut messages = self.messages.lock();
+        messages.push(ScheduledMessage { id: id.clone(), send_at, subscription, payload, format, context, attempts: 0 });
+        self.messages.changed();
+        drop(messages);
+
+        self.wake.notify_one();
+        id
+    }
+
+    ///Devuelve false si no existe un mensaje pendiente con ese id programado por caller
+    pub fn cancel(&self, id: &str, caller: &str) -> bool {
+        let mut messages = self.messages.lock();
+        let before = messages.len();
+        messages.retain(|m| m.id != id || m.context.caller != caller);
+        let removed = messages.len() != before;
+        if removed {
+            self.messages.changed();
+        }
+        removed
+    }
+
+    ///Mensajes que todavia no se enviaron
+    pub fn pending(&self) -> usize {
+        self.messages.lock().len()
+    }
+
+    ///Mensajes vencidos. Quedan en la cola hasta tener un resultado final, asi un reinicio durante el envio no los pierde
+    fn due(&self, now: u64) -> Vec<ScheduledMessage> {
+        self.messages.lock().iter().filter(|m| m.send_at <= now).cloned().collect()
+    }
+
+    ///Cuando vence el proximo mensaje
+    fn next(&self) -> Option<u64> {
+        self.messages.lock().iter().map(|m| m.send_at).min()
+    }
+
+    ///Saca el mensaje de la cola, ya se envio o fallo sin posibilidad de reintentar
+    fn complete(&self, id: &str) {
+        let mut messages = self.messages.lock();
+        messages.retain(|m| m.id != id);
+        self.messages.changed();
+    }
+
+    ///Reprograma el mensaje despues de un error transitorio. Devuelve false si se agotaron los intentos
+    fn retry(&self, id: &str, retry_after: Option<Duration>) -> bool {
+        let mut messages = self.messages.lock();
+        let Some(msg) = messages.iter_mut().find(|m| m.id == id) else {
+            //Se cancelo mientras se enviaba
+            return true;
+        };
+        msg.attempts += 1;
+        if msg.attempts >= MAX_ATTEMPTS {
+            messages.retain(|m| m.id != id);
+            self.messages.changed();
+            return false;
+        }
+        let wait = RETRY_WAIT * 2u32.pow(msg.attempts - 1);
+        let wait = retry_after.unwrap_or_default().max(wait).min(MAX_RETRY_WAIT);
+        msg.send_at = now_millis().saturating_add(wait.as_millis() as u64);
+        self.messages.changed();
+        true
+    }
+}
+
+pub fn now_millis() -> u64 {
+    Utc::now().timestamp_millis().try_into().unwrap()
+}
+
+///Envia los mensajes programados a medida que vencen
+pub async fn run(state: Arc<AppState>) {
+    trace!("Scheduler worker started");
+    loop {
+        for msg in state.scheduler.due(now_millis()) {
+            //Los logs del envio llevan el id de la request que lo programo
+            let span = info_span!("scheduled", id = %msg.id, request_id = %msg.context.request_id);
+            send_due(&state, msg).instrument(span).await;
+        }
+
+        let wait = state.scheduler.next()
+            .map(|t| Duration::from_millis(t.saturating_sub(now_millis())).min(IDLE_WAIT))
+            .unwrap_or(IDLE_WAIT);
+
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {},
+            _ = state.scheduler.wake.notified() => {},
+        }
+    }
+}
+
+async fn send_due(state: &AppState, msg: ScheduledMessage) {
+    let tracking = msg.payload.as_ref().and_then(|p| state.events.track(&msg.id, msg.context.template.as_deref(), None, &p.notification));
+    let payload = match msg.payload.map(|p| build_payload(&state.payload, p, msg.subscription.content_encoding, msg.format, tracking.as_ref())).transpose() {
+        Ok(p) => p,
+        Err(size) => {
+            tracing::error!("Scheduled push {} is too large: {} bytes, max {}", msg.id, size.size, size.max_size);
+            state.scheduler.complete(&msg.id);
+            return;
+        }
+    };
+    //Los webhooks informan si un fallo se va a reintentar
+    let context = SendContext { attempts: msg.attempts, retries_left: MAX_ATTEMPTS.saturating_sub(msg.attempts + 1), ..msg.context };
+    match push::send(state, &msg.subscription, payload.as_ref().map(String::as_bytes), &context).await {
+        Ok(_) => {
+            info!("Scheduled push {} sent", msg.id);
+            state.scheduler.complete(&msg.id);
+            if let Some(tracking) = &tracking {
+                state.events.sent(tracking, &context.caller, 1);
+            }
+        },
+        Err(e) if e.is_transient() => {
+            if state.scheduler.retry(&msg.id, e.retry_after()) {
+                info!("Scheduled push {} failed, will be retried: {}", msg.id, e);
+            } else {
+                tracing::error!("Scheduled push {} failed after {} attempts: {}", msg.id, MAX_ATTEMPTS, e);
+            }
+        },
+        Err(e) => {
+            tracing::error!("Scheduled push {} failed: {}", msg.id, e);
+            state.scheduler.complete(&msg.id);
+        },
+    }
+}
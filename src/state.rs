@@ -0,0 +1,24 @@
+use crate::{audit::Audit, conf::{ApiKey, EndpointsConf, KeysJson, LoggingConf, PayloadConf, ServiceWorkerConf}, events::Events, health::Health, idempotency::Idempotency, metrics::Metrics, rate_limit::RateLimits, recurring::Recurring, scheduler::Scheduler, templates::Templates, topics::Topics, webhooks::Webhooks};
+
+///Estado compartido por todas las rutas
+pub struct AppState {
+    pub keys          : KeysJson,
+    pub api_keys      : Vec<ApiKey>,
+    pub rate_limits   : RateLimits,
+    pub payload       : PayloadConf,
+    pub endpoints     : EndpointsConf,
+    ///Cliente de los push services, con las direcciones verificadas al conectarse si endpoints.enforce esta activo
+    pub push_client   : reqwest::Client,
+    pub scheduler     : Scheduler,
+    pub recurring     : Recurring,
+    pub topics        : Topics,
+    pub templates     : Templates,
+    pub idempotency   : Idempotency,
+    pub metrics       : Metrics,
+    pub health        : Health,
+    pub logging       : LoggingConf,
+    pub audit         : Audit,
+    pub webhooks      : Webhooks,
+    pub events        : Events,
+    pub service_worker: ServiceWorkerConf,
+}
@@ -0,0 +1,85 @@
+use std::{fs, path::PathBuf, sync::{Arc, Mutex, MutexGuard, atomic::{AtomicBool, Ordering}}, time::Duration};
+
+use serde::{Serialize, de::DeserializeOwned};
+use tracing::{debug, trace};
+
+use crate::{conf::data_path, state::AppState};
+
+///Cada cuanto se guardan los cambios
+const FLUSH_EVERY: Duration = Duration::from_secs(1);
+
+///Datos guardados como json junto al ejecutable. Los cambios se marcan con changed y los escribe el worker
+pub struct JsonStore<T> {
+    path : PathBuf,
+    data : Mutex<T>,
+    dirty: AtomicBool,
+}
+
+impl<T: Serialize + DeserializeOwned + Default + Clone> JsonStore<T> {
+    ///Lee el archivo, o empieza vacio si no existe
+    pub fn load(file_name: &str) -> Self {
+        let path = data_path(file_name);
+        trace!("Loading {:?}", &path);
+
+        let data = match fs::read(&path) {
+            Ok(b) => match serde_json::from_slice::<T>(&b) {
+                Ok(d) => d,
+                Err(e) => panic!("{} couldn't be parsed: {}", file_name, e),
+            },
+            Err(_) => {
+                debug!("{} couldn't be found. Starting empty", file_name);
+                T::default()
+            }
+        };
+
+        Self { path, data: Mutex::new(data), dirty: AtomicBool::new(false) }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        self.data.lock().unwrap()
+    }
+
+    ///Marca los datos como modificados, se guardan en el proximo flush
+    pub fn changed(&self) {
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    ///Guarda una copia si hubo cambios, asi no se bloquea a los demas mientras se escribe
+    pub fn flush(&self) {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        let copy = self.lock().clone();
+        self.write(&copy);
+    }
+
+    ///Escribe a un archivo temporal y lo renombra, un corte a mitad de la escritura no deja el archivo truncado
+    pub fn write(&self, data: &T) {
+        let parsed = serde_json::to_string(data).unwrap();
+        let tmp = self.path.with_extension("json.tmp");
+        if let Err(err) = fs::write(&tmp, parsed).and_then(|_| fs::rename(&tmp, &self.path)) {
+            tracing::error!("{} couldn't be saved: {}", self.path.display(), err);
+        }
+    }
+}
+
+///Guarda los cambios de todos los stores
+pub fn flush_all(state: &AppState) {
+    state.scheduler.flush();
+    state.recurring.flush();
+    state.topics.flush();
+    state.templates.flush();
+    state.webhooks.flush();
+}
+
+///Guarda los cambios periodicamente, fuera de las requests
+pub async fn run(state: Arc<AppState>) {
+    trace!("Store worker started");
+    loop {
+        tokio::time::sleep(FLUSH_EVERY).await;
+        let stores = state.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || flush_all(&stores)).await {
+            tracing::error!("Stores couldn't be saved: {}", e);
+        }
+    }
+}
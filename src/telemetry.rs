@@ -0,0 +1,133 @@
+use std::sync::OnceLock;
+
+use axum::http::HeaderMap;
+use opentelemetry::{global, propagation::Extractor, trace::TracerProvider};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{Resource, propagation::TraceContextPropagator, trace::{Sampler, SdkTracerProvider}};
+use tracing::{Span, level_filters::LevelFilter};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::{Layer, filter::Filtered, registry::LookupSpan};
+
+use crate::conf::TelemetryConf;
+
+///Se guarda para exportar los spans pendientes al cerrar
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+///Layer que exporta los spans por OTLP/HTTP. None si no hay endpoint configurado
+///Filtra por su cuenta, independiente de trace_level: request, auth, notify y push_send son INFO
+pub fn layer<S>(conf: &TelemetryConf) -> Option<Filtered<OpenTelemetryLayer<S, opentelemetry_sdk::trace::Tracer>, LevelFilter, S>>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    let provider = provider(conf)?;
+    let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("web_notif"));
+    let _ = PROVIDER.set(provider);
+    Some(layer.with_filter(LevelFilter::INFO))
+}
+
+///Exporta los spans que quedan en el batch. Se llama cuando se detiene el servidor
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get() && let Err(e) = provider.shutdown() {
+        eprintln!("OTLP exporter couldn't be shut down: {}", e);
+    }
+}
+
+fn provider(conf: &TelemetryConf) -> Option<SdkTracerProvider> {
+    let endpoint = conf.endpoint.as_ref()?;
+
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .unwrap_or_else(|e| panic!("OTLP exporter for {} couldn't be built: {}", endpoint, e));
+
+    //Si la request trae traceparent se respeta la decision de muestreo del llamador
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(conf.sample_ratio))))
+        .with_resource(Resource::builder().with_service_name(conf.service_name.clone()).build())
+        .build();
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    global::set_tracer_provider(provider.clone());
+    Some(provider)
+}
+
+///Usa el traceparent de la request como padre del span
+pub fn set_parent(span: &Span, headers: &HeaderMap) {
+    let cx = global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(headers)));
+    let _ = span.set_parent(cx);
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::{BufRead, BufReader, Read, Write}, net::TcpListener, sync::mpsc, thread, time::Duration};
+
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    ///Collector OTLP/HTTP minimo: contesta 200 a cada request y manda la linea de la request y el body por el canal
+    fn collector_stub() -> (String, mpsc::Receiver<(String, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = header.split_once(':') && name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").unwrap();
+                let _ = sender.send((request_line.trim().to_owned(), body));
+            }
+        });
+        (endpoint, receiver)
+    }
+
+    #[test]
+    fn exports_spans_to_the_collector() {
+        let (endpoint, received) = collector_stub();
+        let conf = TelemetryConf { endpoint: Some(endpoint), sample_ratio: 1.0, service_name: "web_notif_test".to_owned() };
+        let provider = provider(&conf).unwrap();
+        let subscriber = tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(provider.tracer("web_notif")));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("push_send", origin = "https://fcm.googleapis.com").in_scope(|| {});
+        });
+        provider.force_flush().unwrap();
+
+        let (request_line, body) = received.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(request_line, "POST /v1/traces HTTP/1.1");
+        //El body es protobuf, los strings van sin codificar
+        let contains = |text: &str| body.windows(text.len()).any(|w| w == text.as_bytes());
+        assert!(contains("web_notif_test"));
+        assert!(contains("push_send"));
+        assert!(contains("https://fcm.googleapis.com"));
+    }
+}
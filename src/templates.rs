@@ -0,0 +1,217 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{routes::notify::{Action, Notification}, store::JsonStore};
+
+///Textos de una plantilla en un idioma. Admiten {{variable}}
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone)]
+pub struct TemplateVariant {
+    pub title  : String,
+    pub body   : Option<String>,
+    pub icon   : Option<String>,
+    pub actions: Option<Vec<Action>>,
+}
+
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone)]
+pub struct Template {
+    ///Variants by BCP 47 language tag, compared case-insensitively. Ej: `{"en": {...}, "es-AR": {...}}`
+    pub variants: BTreeMap<String, TemplateVariant>,
+}
+
+impl Template {
+    ///Primera variante de la cadena. Los tags de BCP 47 no distinguen mayusculas
+    fn variant(&self, chain: &[String]) -> Option<(&String, &TemplateVariant)> {
+        chain.iter().find_map(|l| self.variants.iter().find(|(lang, _)| lang.eq_ignore_ascii_case(l)))
+    }
+}
+
+///Reference to a stored template used instead of a full notification
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone)]
+pub struct TemplateRef {
+    pub id       : String,
+    ///Values for the {{placeholders}} of the template
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
+    ///Preferred BCP 47 language tag. If the variant is missing the configured fallback chain is used
+    pub locale   : Option<String>,
+}
+
+///Plantillas de cada api key, guardadas en templates.json junto al ejecutable
+pub struct Templates {
+    ///Por caller y despues por id, cada api key tiene sus propias plantillas
+    templates: JsonStore<BTreeMap<String, BTreeMap<String, Template>>>,
+    ///Idiomas a probar, en orden, cuando no existe la variante pedida
+    fallback : Vec<String>,
+}
+
+impl Templates {
+    pub fn load(fallback: Vec<String>) -> Self {
+        Self { templates: JsonStore::load("templates.json"), fallback }
+    }
+
+    ///Guarda los cambios, lo llama el worker de store
+    pub fn flush(&self) {
+        self.templates.flush();
+    }
+
+    ///Plantillas creadas por caller
+    pub fn list(&self, caller: &str) -> BTreeMap<String, Template> {
+        self.templates.lock().get(caller).cloned().unwrap_or_default()
+    }
+
+    pub fn get(&self, caller: &str, id: &str) -> Option<Template> {
+        self.templates.lock().get(caller)?.get(id).cloned()
+    }
+
+    pub fn upsert(&self, caller: &str, id: &str, template: Template) {
+        let mut templates = self.templates.lock();
+        templates.entry(caller.to_owned()).or_default().insert(id.to_owned(), template);
+        self.templates.changed();
+    }
+
+    pub fn delete(&self, caller: &str, id: &str) -> bool {
+        let mut templates = self.templates.lock();
+        let Some(owned) = templates.get_mut(caller) else {
+            return false;
+        };
+        let removed = owned.remove(id).is_some();
+        if owned.is_empty() {
+            templates.remove(caller);
+        }
+        if removed {
+            self.templates.changed();
+        }
+        removed
+    }
+
+    ///Arma la notificacion con la variante que corresponda y las variables reemplazadas.
+    ///Solo se usan las plantillas de caller
+    pub fn render(&self, caller: &str, reference: &TemplateRef) -> Result<Notification, String> {
+        let template = self.get(caller, &reference.id)
+            .ok_or_else(|| format!("Template {} doesn't exist", reference.id))?;
+
+        let (lang, variant) = template.variant(&locale_chain(reference.locale.as_deref(), &self.fallback))
+            .ok_or_else(|| format!("Template {} has no variant for the requested locale or its fallbacks", reference.id))?;
+
+        let mut missing = Vec::new();
+        let mut fill = |text: &str| substitute(text, &reference.variables, &mut missing);
+
+        let notification = Notification {
+            title  : fill(&variant.title),
+            body   : variant.body.as_deref().map(&mut fill),
+            icon   : variant.icon.as_deref().map(&mut fill),
+            actions: variant.actions.as_ref().map(|actions| actions.iter().map(|a| Action {
+                action   : a.action.clone(),
+                title    : fill(&a.title),
+                icon     : a.icon.as_deref().map(&mut fill),
+                operation: a.operation.clone(),
+                url      : a.url.as_deref().map(&mut fill),
+                data     : a.data.clone(),
+            }).collect()),
+            lang   : Some(lang.clone()),
+            ..Default::default()
+        };
+
+        if missing.is_empty() {
+            Ok(notification)
+        } else {
+            missing.sort();
+            missing.dedup();
+            Err(format!("Missing template variables: {}", missing.join(", ")))
+        }
+    }
+}
+
+///Idiomas a probar: el pedido, sus prefijos (es-AR -> es) y la cadena configurada
+fn locale_chain(requested: Option<&str>, fallback: &[String]) -> Vec<String> {
+    let mut chain = Vec::new();
+    if let Some(mut tag) = requested {
+        loop {
+            chain.push(tag.to_owned());
+            match tag.rfind('-') {
+                Some(i) => tag = &tag[..i],
+                None => break,
+            }
+        }
+    }
+    for l in fallback {
+        if !chain.iter().any(|c| c.eq_ignore_ascii_case(l)) {
+            chain.push(l.clone());
+        }
+    }
+    chain
+}
+
+///Reemplaza {{variable}}. Las variables sin valor se agregan a missing, {{}} sin nombre queda como texto
+fn substitute(text: &str, variables: &HashMap<String, String>, missing: &mut Vec<String>) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start + 2..].find("}}") else {
+            break;
+        };
+        let end = start + 2 + len + 2;
+        let name = rest[start + 2..end - 2].trim();
+        match variables.get(name) {
+            _ if name.is_empty() => out.push_str(&rest[..end]),
+            Some(value) => {
+                out.push_str(&rest[..start]);
+                out.push_str(value);
+            },
+            None => {
+                out.push_str(&rest[..start]);
+                missing.push(name.to_owned());
+            },
+        }
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variant(title: &str) -> TemplateVariant {
+        TemplateVariant { title: title.to_owned(), body: None, icon: None, actions: None }
+    }
+
+    #[test]
+    fn locale_chain_tries_prefixes_and_then_the_fallback() {
+        let fallback = vec!["en".to_owned(), "ES".to_owned()];
+        assert_eq!(locale_chain(Some("es-AR"), &fallback), ["es-AR", "es", "en"]);
+        assert_eq!(locale_chain(Some("zh-Hant-TW"), &fallback), ["zh-Hant-TW", "zh-Hant", "zh", "en", "ES"]);
+        assert_eq!(locale_chain(None, &fallback), ["en", "ES"]);
+    }
+
+    #[test]
+    fn variants_are_found_ignoring_case() {
+        let template = Template { variants: BTreeMap::from([("es-AR".to_owned(), variant("Hola")), ("en".to_owned(), variant("Hi"))]) };
+        let found = |requested| template.variant(&locale_chain(Some(requested), &[])).map(|(lang, v)| (lang.as_str(), v.title.as_str()));
+
+        assert_eq!(found("ES-ar"), Some(("es-AR", "Hola")));
+        assert_eq!(found("EN-us"), Some(("en", "Hi")));
+        assert_eq!(found("fr"), None);
+    }
+
+    #[test]
+    fn substitute_reports_each_missing_variable() {
+        let variables = HashMap::from([("name".to_owned(), "Ana".to_owned())]);
+        let mut missing = Vec::new();
+
+        assert_eq!(substitute("Hi {{ name }}, order {{order}} {{date}}", &variables, &mut missing), "Hi Ana, order  ");
+        assert_eq!(missing, ["order", "date"]);
+    }
+
+    #[test]
+    fn substitute_keeps_empty_and_unclosed_placeholders() {
+        let mut missing = Vec::new();
+
+        assert_eq!(substitute("{{}} and {{ }} stay", &HashMap::new(), &mut missing), "{{}} and {{ }} stay");
+        assert_eq!(substitute("a {{name", &HashMap::new(), &mut missing), "a {{name");
+        assert!(missing.is_empty());
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::{BTreeMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{routes::notify::Subscription, store::JsonStore, validation::FieldError};
+
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone, Default)]
+pub struct TopicStats {
+    ///Amount of times the topic was published
+    pub published: u64,
+    ///Pushes accepted by the push services
+    pub sent     : u64,
+    ///Pushes rejected by the push services
+    pub failed   : u64,
+    ///Subscriptions removed because the push service reported them as gone
+    pub expired  : u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct Topic {
+    pub members: Vec<Subscription>,
+    pub stats  : TopicStats,
+}
+
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone)]
+pub struct TopicSummary {
+    pub name   : String,
+    pub members: usize,
+    pub stats  : TopicStats,
+}
+
+///Resultado de una publicacion
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone, Default)]
+pub struct PublishResult {
+    pub sent      : u64,
+    pub failed    : u64,
+    pub expired   : u64,
+    ///Id of the message in GET /stats/messages/{id}
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    ///Fields ignored by the browsers of some members, with how many of them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings  : Vec<FieldError>,
+}
+
+///Topicos de cada api key y sus miembros, persistidos en topics.json junto al ejecutable
+pub struct Topics {
+    ///Por caller y despues por nombre, cada api key tiene sus propios topicos
+    topics: JsonStore<BTreeMap<String, BTreeMap<String, Topic>>>,
+}
+
+impl Topics {
+    pub fn load() -> Self {
+        Self { topics: JsonStore::load("topics.json") }
+    }
+
+    ///Guarda los cambios, lo llama el worker de store
+    pub fn flush(&self) {
+        self.topics.flush();
+    }
+
+    ///Topicos creados por caller
+    pub fn list(&self, caller: &str) -> Vec<TopicSummary> {
+        self.topics.lock()
+            .get(caller)
+            .into_iter()
+            .flatten()
+            .map(|(name, t)| TopicSummary { name: name.clone(), members: t.members.len(), stats: t.stats.clone() })
+            .collect()
+    }
+
+    ///Crea el topico si no existe. Si el endpoint ya estaba se reemplazan sus claves
+    pub fn attach(&self, caller: &str, name: &str, sub: Subscription) {
+        let mut topics = self.topics.lock();
+        let topic = topics.entry(caller.to_owned()).or_default().entry(name.to_owned()).or_default();
+        topic.members.retain(|m| m.endpoint != sub.endpoint);
+        topic.members.push(sub);
+        self.topics.changed();
+    }
+
+    ///Devuelve false si el endpoint no pertenecia al topico
+    pub fn detach(&self, caller: &str, name: &str, endpoint: &str) -> bool {
+        let mut topics = self.topics.lock();
+        let Some(owned) = topics.get_mut(caller) else {
+            return false;
+        };
+        let Some(topic) = owned.get_mut(name) else {
+            return false;
+        };
+        let before = topic.members.len();
+        topic.members.retain(|m| m.endpoint != endpoint);
+        let removed = topic.members.len() != before;
+        if topic.members.is_empty() {
+            owned.remove(name);
+        }
+        if owned.is_empty() {
+            topics.remove(caller);
+        }
+        if removed {
+            self.topics.changed();
+        }
+        removed
+    }
+
+    ///Endpoints distintos entre todos los topicos de todas las api keys
+    pub fn endpoints(&self) -> HashSet<String> {
+        self.topics.lock()
+            .values()
+            .flat_map(|owned| owned.values())
+            .flat_map(|t| t.members.iter().map(|m| m.endpoint.clone()))
+            .collect()
+    }
+
+    pub fn members(&self, caller: &str, name: &str) -> Option<Vec<Subscription>> {
+        self.topics.lock().get(caller)?.get(name).map(|t| t.members.clone())
+    }
+
+    ///Acumula el resultado de una publicacion y quita las suscripciones vencidas
+    pub fn record(&self, caller: &str, name: &str, result: &PublishResult, expired: &[String]) {
+        let mut topics = self.topics.lock();
+        let Some(topic) = topics.get_mut(caller).and_then(|owned| owned.get_mut(name)) else {
+            return;
+        };
+        topic.members.retain(|m| !expired.contains(&m.endpoint));
+        topic.stats.published += 1;
+        topic.stats.sent      += result.sent;
+        topic.stats.failed    += result.failed;
+        topic.stats.expired   += result.expired;
+        self.topics.changed();
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::HashSet;
+
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use serde::{Deserialize, Serialize};
+use url::Url;
+use utoipa::ToSchema;
+
+use crate::{routes::notify::{Action, Notification, Subscription}, templates::Template};
+
+///A field that failed validation
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone)]
+pub struct FieldError {
+    ///Path of the field in the request. Ej: `payload.notification.actions[2].url`
+    pub path   : String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(path: impl Into<String>, message: impl Into<String>) -> Self {
+        Self { path: path.into(), message: message.into() }
+    }
+}
+
+///Valida la suscripcion. Que el endpoint sea de un push service permitido lo valida allowlist::check,
+///aca solo la sintaxis, que hace falta aunque enforce este apagado
+pub fn subscription(sub: &Subscription, path: &str, errors: &mut Vec<FieldError>) {
+    let endpoint = join(path, "endpoint");
+    //Con enforce allowlist::check ya pudo haber rechazado el endpoint
+    if !errors.iter().any(|e| e.path == endpoint) {
+        match Url::parse(&sub.endpoint) {
+            Ok(url) if !matches!(url.scheme(), "https" | "http") => errors.push(FieldError::new(endpoint, "must be an http(s) URL")),
+            Ok(url) if url.host_str().is_none() => errors.push(FieldError::new(endpoint, "has no host")),
+            Ok(_) => {},
+            Err(e) => errors.push(FieldError::new(endpoint, format!("is not a valid URL: {e}"))),
+        }
+    }
+
+    match decode_key(&sub.keys.p256dh) {
+        Some(k) if k.len() == 65 && k[0] == 0x04 => {},
+        Some(_) => errors.push(FieldError::new(join(path, "keys.p256dh"), "must be an uncompressed P-256 public key (65 bytes)")),
+        None => errors.push(FieldError::new(join(path, "keys.p256dh"), "is not valid base64url")),
+    }
+
+    match decode_key(&sub.keys.auth) {
+        Some(k) if k.len() == 16 => {},
+        Some(_) => errors.push(FieldError::new(join(path, "keys.auth"), "must be 16 bytes")),
+        None => errors.push(FieldError::new(join(path, "keys.auth"), "is not valid base64url")),
+    }
+}
+
+///Valida los campos de la notificacion
+pub fn notification(n: &Notification, path: &str, errors: &mut Vec<FieldError>) {
+    if n.title.trim().is_empty() {
+        errors.push(FieldError::new(format!("{path}.title"), "can't be empty"));
+    }
+
+    for (field, value) in [("badge", &n.badge), ("icon", &n.icon), ("image", &n.image)] {
+        if let Some(value) = value {
+            url_or_path(value, &format!("{path}.{field}"), errors);
+        }
+    }
+
+    if let Some(lang) = &n.lang && !is_language_tag(lang) {
+        errors.push(FieldError::new(format!("{path}.lang"), "is not a valid BCP 47 language tag"));
+    }
+
+    let mut ids = HashSet::new();
+    for (i, action) in n.actions.iter().flatten().enumerate() {
+        if action.title.trim().is_empty() {
+            errors.push(FieldError::new(format!("{path}.actions[{i}].title"), "can't be empty"));
+        }
+        if action.action.as_deref().is_some_and(|a| a.trim().is_empty()) {
+            errors.push(FieldError::new(format!("{path}.actions[{i}].action"), "can't be empty"));
+        }
+        let id = action.id(i);
+        if !ids.insert(id.clone()) {
+            errors.push(FieldError::new(format!("{path}.actions[{i}].action"), format!("duplicated action id {id}")));
+        }
+        if let Some(url) = &action.url {
+            url_or_path(url, &format!("{path}.actions[{i}].url"), errors);
+        }
+        if let Some(icon) = &action.icon {
+            url_or_path(icon, &format!("{path}.actions[{i}].icon"), errors);
+        }
+    }
+
+    //onActionClick se agrega dentro de data
+    if !ids.is_empty() && n.data.as_ref().is_some_and(|d| !d.is_object()) {
+        errors.push(FieldError::new(format!("{path}.data"), "must be an object when actions are set"));
+    }
+}
+
+///Valida los idiomas y los campos de cada variante. Las urls con {{variable}} se validan al renderizar, con los valores
+pub fn template(t: &Template, errors: &mut Vec<FieldError>) {
+    if t.variants.is_empty() {
+        errors.push(FieldError::new("variants", "needs at least one variant"));
+    }
+
+    let mut langs = HashSet::new();
+    for (lang, variant) in &t.variants {
+        let path = format!("variants.{lang}");
+        if !is_language_tag(lang) {
+            errors.push(FieldError::new(&path, "is not a valid BCP 47 language tag"));
+        } else if !langs.insert(lang.to_ascii_lowercase()) {
+            errors.push(FieldError::new(&path, "is repeated, language tags are case-insensitive"));
+        }
+
+        let fixed = |value: &Option<String>| value.clone().filter(|v| !v.contains("{{"));
+        let n = Notification {
+            title  : variant.title.clone(),
+            icon   : fixed(&variant.icon),
+            actions: variant.actions.as_ref().map(|actions| actions.iter().map(|a| Action {
+                icon: fixed(&a.icon),
+                url : fixed(&a.url),
+                ..a.clone()
+            }).collect()),
+            ..Default::default()
+        };
+        notification(&n, &path, errors);
+    }
+}
+
+///Ruta del campo dentro de path. Path vacio cuando el body es el objeto validado
+fn join(path: &str, field: &str) -> String {
+    if path.is_empty() {
+        field.to_owned()
+    } else {
+        format!("{path}.{field}")
+    }
+}
+
+///Acepta urls absolutas http(s) o rutas relativas al origen del service worker
+fn url_or_path(value: &str, path: &str, errors: &mut Vec<FieldError>) {
+    if value.starts_with('/') && !value.starts_with("//") {
+        return;
+    }
+    match Url::parse(value) {
+        Ok(url) if url.scheme() == "https" || url.scheme() == "http" => {},
+        Ok(_) => errors.push(FieldError::new(path, "must be an http(s) URL or a path starting with /")),
+        Err(e) => errors.push(FieldError::new(path, format!("is not a valid URL: {e}"))),
+    }
+}
+
+///Claves de PushSubscription en base64url, con o sin padding
+fn decode_key(value: &str) -> Option<Vec<u8>> {
+    BASE64_URL_SAFE_NO_PAD.decode(value.trim_end_matches('=')).ok()
+}
+
+///Sintaxis de BCP 47: idioma de 2-3 o 5-8 letras y subtags alfanumericos de 1-8
+fn is_language_tag(tag: &str) -> bool {
+    let mut parts = tag.split('-');
+    let lang_ok = parts.next().is_some_and(|l| matches!(l.len(), 2..=3 | 5..=8) && l.chars().all(|c| c.is_ascii_alphabetic()));
+    lang_ok && parts.all(|p| (1..=8).contains(&p.len()) && p.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn paths(errors: &[FieldError]) -> Vec<&str> {
+        errors.iter().map(|e| e.path.as_str()).collect()
+    }
+
+    fn subscription_with(endpoint: &str, p256dh: &str, auth: &str) -> Subscription {
+        serde_json::from_value(json!({ "endpoint": endpoint, "keys": { "p256dh": p256dh, "auth": auth } })).unwrap()
+    }
+
+    #[test]
+    fn subscription_reports_invalid_keys() {
+        let p256dh = BASE64_URL_SAFE_NO_PAD.encode([4; 65]);
+        let auth = BASE64_URL_SAFE_NO_PAD.encode([1; 16]);
+
+        let mut errors = Vec::new();
+        subscription(&subscription_with("https://fcm.googleapis.com/a", &p256dh, &format!("{auth}==")), "subscription", &mut errors);
+        assert!(errors.is_empty(), "{errors:?}");
+
+        //Clave comprimida de 33 bytes y auth de 15
+        let compressed = BASE64_URL_SAFE_NO_PAD.encode([2; 33]);
+        let short = BASE64_URL_SAFE_NO_PAD.encode([1; 15]);
+        subscription(&subscription_with("ftp://fcm.googleapis.com/a", &compressed, &short), "subscription", &mut errors);
+        assert_eq!(paths(&errors), ["subscription.endpoint", "subscription.keys.p256dh", "subscription.keys.auth"]);
+
+        //Sin path cuando la suscripcion es todo el body
+        let mut errors = Vec::new();
+        subscription(&subscription_with("not a url", "no base64!", &auth), "", &mut errors);
+        assert_eq!(paths(&errors), ["endpoint", "keys.p256dh"]);
+        assert_eq!(errors[1].message, "is not valid base64url");
+    }
+
+    #[test]
+    fn notification_reports_the_path_of_each_field() {
+        let n: Notification = serde_json::from_value(json!({
+            "title": " ",
+            "icon": "/icon.png",
+            "badge": "//cdn.example.com/badge.png",
+            "lang": "en_US",
+            "actions": [
+                { "title": "Open", "operation": "openWindow", "url": "/orders/1" },
+                { "title": "Site", "operation": "openWindow", "url": "https://example.com" },
+                { "title": "Run", "operation": "openWindow", "url": "javascript:alert(1)" },
+            ],
+        })).unwrap();
+        let mut errors = Vec::new();
+        notification(&n, "payload.notification", &mut errors);
+
+        assert_eq!(paths(&errors), ["payload.notification.title", "payload.notification.badge", "payload.notification.lang", "payload.notification.actions[2].url"]);
+    }
+
+    #[test]
+    fn notification_rejects_duplicated_action_ids() {
+        //La segunda repite el id explicito, la tercera el A1 que se le asigna a la primera por posicion
+        let n: Notification = serde_json::from_value(json!({
+            "title": "Hi",
+            "actions": [
+                { "title": "One", "operation": "openWindow" },
+                { "action": "reply", "title": "Reply", "operation": "sendRequest" },
+                { "action": "reply", "title": "Again", "operation": "sendRequest" },
+                { "action": "A1", "title": "Same", "operation": "openWindow" },
+            ],
+        })).unwrap();
+        let mut errors = Vec::new();
+        notification(&n, "notification", &mut errors);
+
+        assert_eq!(paths(&errors), ["notification.actions[2].action", "notification.actions[3].action"]);
+        assert_eq!(errors[0].message, "duplicated action id reply");
+    }
+
+    #[test]
+    fn language_tags() {
+        for tag in ["en", "es-AR", "es-419", "zh-Hant-TW", "de-CH-1996"] {
+            assert!(is_language_tag(tag), "{tag}");
+        }
+        for tag in ["", "e", "en_US", "en-", "abcd", "es-toolongsubtag", "123"] {
+            assert!(!is_language_tag(tag), "{tag}");
+        }
+    }
+
+    #[test]
+    fn template_checks_languages_and_urls_without_placeholders() {
+        let t: Template = serde_json::from_value(json!({ "variants": {
+            "en": { "title": "Hi", "actions": [
+                { "title": "Open", "operation": "openWindow", "url": "https://{{host}}/orders/{{order}}" },
+                { "title": "Track", "operation": "openWindow", "url": "ftp://example.com" },
+            ] },
+            "EN": { "title": "Hi" },
+            "english!": { "title": "Hi", "icon": "{{icon}}" },
+        } })).unwrap();
+        let mut errors = Vec::new();
+        template(&t, &mut errors);
+
+        assert_eq!(paths(&errors), ["variants.en", "variants.en.actions[1].url", "variants.english!"]);
+    }
+}
@@ -0,0 +1,226 @@
+use std::time::Duration;
+
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::{debug, info};
+use utoipa::ToSchema;
+
+use crate::{allowlist, audit::SendContext, conf::{EndpointsConf, WebhooksConf}, push::PushError, scheduler::now_millis, store::JsonStore};
+
+///Espera maxima entre reintentos
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all="snake_case")]
+pub enum EventKind {
+    ///The push service accepted the push
+    Delivered,
+    ///The push couldn't be sent
+    Failed,
+    ///The push service reported that the subscription no longer exists
+    SubscriptionExpired,
+}
+
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone)]
+pub struct WebhookRequest {
+    ///Receives a POST with a json event for each push of the api key
+    pub url   : String,
+    ///Events sent to the url. Defaults to all
+    #[serde(default = "all_events")]
+    pub events: Vec<EventKind>,
+}
+
+fn all_events() -> Vec<EventKind> {
+    vec![EventKind::Delivered, EventKind::Failed, EventKind::SubscriptionExpired]
+}
+
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone)]
+pub struct Webhook {
+    pub id : String,
+    #[serde(flatten)]
+    pub def: WebhookRequest,
+}
+
+///Returned only when the webhook is registered
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone)]
+pub struct CreatedWebhook {
+    #[serde(flatten)]
+    pub webhook: Webhook,
+    ///Key of the HMAC-SHA256 signature sent in X-Webhook-Signature
+    pub secret : String,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+struct StoredWebhook {
+    ///Api key que lo registro, solo recibe los eventos de sus envios
+    caller : String,
+    secret : String,
+    #[serde(flatten)]
+    webhook: Webhook,
+}
+
+///Body sent to the webhooks
+#[derive(Deserialize, Serialize, ToSchema, Debug, Clone)]
+pub struct WebhookEvent {
+    ///Same in every retry of the event
+    pub id        : String,
+    #[serde(rename = "type")]
+    pub kind      : EventKind,
+    ///Unix time in milliseconds
+    pub time      : u64,
+    ///Endpoint of the subscription
+    pub target    : String,
+    pub request_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub template  : Option<String>,
+    ///Error of failed pushes, as in the metrics
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error     : Option<String>,
+    ///1 for the first attempt of the push, higher for retries of scheduled pushes
+    pub attempt   : u32,
+    ///The push failed and will be retried, its outcome comes in a later event. Only events with `false` are final
+    pub will_retry: bool,
+}
+
+///Webhooks por api key, persistidos en webhooks.json junto al ejecutable
+pub struct Webhooks {
+    hooks    : JsonStore<Vec<StoredWebhook>>,
+    client   : reqwest::Client,
+    conf     : WebhooksConf,
+    ///Las urls se vuelven a verificar en cada intento, pueden ser ip que el resolver no ve
+    endpoints: EndpointsConf,
+}
+
+impl Webhooks {
+    pub fn load(conf: WebhooksConf, endpoints: &EndpointsConf) -> Self {
+        let client = allowlist::client(endpoints, reqwest::Client::builder().timeout(Duration::from_secs(conf.timeout_secs)));
+        Self { hooks: JsonStore::load("webhooks.json"), client, conf, endpoints: endpoints.clone() }
+    }
+
+    ///Guarda los cambios, lo llama el worker de store
+    pub fn flush(&self) {
+        self.hooks.flush();
+    }
+
+    pub fn list(&self, caller: &str) -> Vec<Webhook> {
+        self.hooks.lock()
+            .iter()
+            .filter(|h| h.caller == caller)
+            .map(|h| h.webhook.clone())
+            .collect()
+    }
+
+    pub fn create(&self, caller: &str, def: WebhookRequest) -> CreatedWebhook {
+        let mut secret = [0u8; 32];
+        openssl::rand::rand_bytes(&mut secret).unwrap();
+
+        let stored = StoredWebhook {
+            caller : caller.to_owned(),
+            secret : BASE64_URL_SAFE_NO_PAD.encode(secret),
+            webhook: Webhook { id: uuid::Uuid::new_v4().to_string(), def },
+        };
+        let created = CreatedWebhook { webhook: stored.webhook.clone(), secret: stored.secret.clone() };
+
+        let mut hooks = self.hooks.lock();
+        hooks.push(stored);
+        self.hooks.changed();
+        created
+    }
+
+    ///Devuelve false si no existe o es de otra api key
+    pub fn delete(&self, caller: &str, id: &str) -> bool {
+        let mut hooks = self.hooks.lock();
+        let before = hooks.len();
+        hooks.retain(|h| !(h.caller == caller && h.webhook.id == id));
+        let removed = hooks.len() != before;
+        if removed {
+            self.hooks.changed();
+        }
+        removed
+    }
+
+    ///Envia el resultado del push a los webhooks de la api key, en segundo plano
+    pub fn dispatch(&self, context: &SendContext, endpoint: &str, result: &Result<(), PushError>) {
+        let kind = match result {
+            Ok(_) => EventKind::Delivered,
+            Err(e) if e.is_expired() => EventKind::SubscriptionExpired,
+            Err(_) => EventKind::Failed,
+        };
+
+        let targets: Vec<(String, String)> = self.hooks.lock()
+            .iter()
+            .filter(|h| h.caller == context.caller && h.webhook.def.events.contains(&kind))
+            .map(|h| (h.webhook.def.url.clone(), h.secret.clone()))
+            .collect();
+        if targets.is_empty() {
+            return;
+        }
+
+        let event = WebhookEvent {
+            id        : uuid::Uuid::new_v4().to_string(),
+            kind,
+            time      : now_millis(),
+            target    : endpoint.to_owned(),
+            request_id: context.request_id.clone(),
+            template  : context.template.clone(),
+            error     : result.as_ref().err().map(|e| e.class().to_owned()),
+            attempt   : context.attempts + 1,
+            will_retry: context.retries_left > 0 && result.as_ref().is_err_and(PushError::is_transient),
+        };
+        let body = serde_json::to_string(&event).unwrap();
+
+        for (url, secret) in targets {
+            tokio::spawn(deliver(self.client.clone(), self.endpoints.clone(), url, secret, event.id.clone(), body.clone(), self.conf.max_attempts));
+        }
+    }
+}
+
+///Reintenta con espera exponencial hasta que el webhook responda 2xx
+async fn deliver(client: reqwest::Client, endpoints: EndpointsConf, url: String, secret: String, event_id: String, body: String, max_attempts: u32) {
+    let mut backoff = Duration::from_secs(1);
+    for attempt in 1..=max_attempts {
+        if let Err(msg) = allowlist::check_webhook(&endpoints, &url).await {
+            tracing::error!("Webhook {} isn't allowed anymore, event {} dropped: {}", url, event_id, msg);
+            return;
+        }
+
+        //Se firma en cada intento, el timestamp cambia
+        let timestamp = (now_millis() / 1000).to_string();
+        let signature = sign(&secret, &timestamp, &body);
+
+        let result = client.post(&url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("X-Webhook-Id", &event_id)
+            .header("X-Webhook-Timestamp", &timestamp)
+            .header("X-Webhook-Signature", format!("sha256={}", signature))
+            .body(body.clone())
+            .send()
+            .await;
+
+        match result {
+            Ok(response) if response.status().is_success() => {
+                debug!("Webhook event {} delivered to {}", event_id, url);
+                return;
+            },
+            Ok(response) => info!("Webhook {} answered {} to event {}, attempt {}/{}", url, response.status(), event_id, attempt, max_attempts),
+            Err(e) => info!("Webhook {} failed for event {}, attempt {}/{}: {}", url, event_id, attempt, max_attempts, e),
+        }
+
+        if attempt < max_attempts {
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+    tracing::error!("Webhook event {} couldn't be delivered to {}", event_id, url);
+}
+
+///HMAC-SHA256 en hex de `{timestamp}.{body}`
+fn sign(secret: &str, timestamp: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    format!("{:x}", mac.finalize().into_bytes())
+}
@@ -32,7 +32,7 @@ fn service_main(_args: Vec<OsString>) {
 }
 
 fn service_main_inner() -> anyhow::Result<()> {
-    let (router, addr) = init_server();
+    let (router, addr, state) = init_server();
 
     trace!("Service main started");
     let (stop_tx, stop_rx_worker) = mpsc::channel();
@@ -75,7 +75,7 @@ fn service_main_inner() -> anyhow::Result<()> {
         let rt = tokio::runtime::Runtime::new().unwrap();
         rt.block_on(async {
             tokio::select! {
-                res = crate::run_server(router, addr) => {
+                res = crate::run_server(router, addr, state) => {
                     if let Err(e) = res {
                         eprintln!("Server exited: {:?}", e);
                     }
@@ -86,6 +86,7 @@ fn service_main_inner() -> anyhow::Result<()> {
                 }
             }
         });
+        crate::telemetry::shutdown();
     });
 
     // Esperara a que inicie tokio